@@ -0,0 +1,385 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+
+use crate::message_bus::{Fault, FaultSchedule, PublishHook, SimTime, Simulator};
+
+/// Environment variable that restricts a sweep to a single seed, to reproduce a failure, see
+/// [Sweep::from_env].
+pub const SEED_ENV: &str = "DSIM_SEED";
+
+/// Runs a simulation for many seeds, see [sweep].
+#[derive(Debug, Clone)]
+pub struct Sweep {
+    run_for: std::time::Duration,
+    step_by: Option<std::time::Duration>,
+    threads: usize,
+    shrink: bool,
+    /// Whether [SEED_ENV] overrides the seeds
+    from_env: bool,
+}
+
+impl Sweep {
+    /// Runs every simulation for `run_for` of virtual time, event-driven (see
+    /// [Simulator::run_until]), on as many threads as there are cores.
+    pub fn new(run_for: std::time::Duration) -> Self {
+        Self {
+            run_for,
+            step_by: None,
+            threads: std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+            shrink: true,
+            from_env: false,
+        }
+    }
+
+    /// Steps every simulation by fixed `step_by` increments instead (see [Simulator::step_to]).
+    pub fn with_step(mut self, step_by: std::time::Duration) -> Self {
+        self.step_by = Some(step_by);
+        self
+    }
+
+    /// Runs the seeds on `threads` threads. Each simulation still runs on a single thread, so
+    /// results don't depend on this.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Runs only the seed in the [SEED_ENV] environment variable instead, if it is set. Failures
+    /// then come with a command that reruns just their seed this way, see
+    /// [SweepFailure::reproduce].
+    pub fn from_env(mut self) -> Self {
+        self.from_env = true;
+        self
+    }
+
+    /// Reports failing seeds as they are, without shrinking them (see [Sweep::shrink]).
+    pub fn without_shrinking(mut self) -> Self {
+        self.shrink = false;
+        self
+    }
+
+    /// Builds a simulation for every seed with `build` and runs it. A seed fails if building or
+    /// running its simulation panics, typically because an invariant registered with
+    /// [Simulator::with_invariant] was violated. Failing seeds are then shrunk, see
+    /// [Sweep::shrink].
+    ///
+    /// Panic messages of failing seeds are collected into the report rather than printed.
+    pub fn run<H, F>(&self, seeds: impl IntoIterator<Item = u64>, build: F) -> SweepReport
+    where
+        H: PublishHook,
+        F: Fn(u64) -> Simulator<H> + Sync,
+    {
+        let override_seed = self.from_env.then(|| std::env::var(SEED_ENV).ok()).flatten();
+        let seeds: Vec<u64> = match override_seed {
+            Some(seed) => vec![
+                seed.parse()
+                    .unwrap_or_else(|_| panic!("{} must be a u64, got {:?}", SEED_ENV, seed)),
+            ],
+            None => seeds.into_iter().collect(),
+        };
+        // Test threads are named after their test, which is what reproduces a failure
+        let test = std::thread::current()
+            .name()
+            .filter(|name| *name != "main")
+            .map(str::to_string);
+
+        let next = AtomicUsize::new(0);
+        let failures = Mutex::new(vec![]);
+        std::thread::scope(|scope| {
+            for _ in 0..self.threads.min(seeds.len()) {
+                scope.spawn(|| {
+                    while let Some(&seed) = seeds.get(next.fetch_add(1, Ordering::SeqCst)) {
+                        let run = self.run_seed(seed, &build, None, self.run_for);
+                        let Err(message) = run.result.clone() else {
+                            continue;
+                        };
+                        let shrunk = self.shrink.then(|| self.shrink_run(seed, &build, run));
+                        failures.lock().unwrap().push(SweepFailure {
+                            seed,
+                            message,
+                            reproduce: self.from_env.then(|| reproduce(seed, test.as_deref())),
+                            shrunk,
+                        });
+                    }
+                });
+            }
+        });
+
+        let mut failures = failures.into_inner().unwrap();
+        failures.sort_by_key(|failure| failure.seed);
+        SweepReport {
+            seeds: seeds.len(),
+            failures,
+        }
+    }
+
+    /// Minimizes the failure of `seed`: records the faults injected into the failing run, then
+    /// runs it again with fewer and fewer of them (see [Simulator::with_fault_schedule]) while it
+    /// keeps failing, each time only up to the time of the failure.
+    ///
+    /// Returns `None` if the seed doesn't fail.
+    pub fn shrink<H, F>(&self, seed: u64, build: F) -> Option<Shrunk>
+    where
+        H: PublishHook,
+        F: Fn(u64) -> Simulator<H>,
+    {
+        let run = self.run_seed(seed, &build, None, self.run_for);
+        run.result.as_ref().err()?;
+        Some(self.shrink_run(seed, &build, run))
+    }
+
+    fn shrink_run<H, F>(&self, seed: u64, build: &F, failed: Run) -> Shrunk
+    where
+        H: PublishHook,
+        F: Fn(u64) -> Simulator<H>,
+    {
+        let fails = |faults: &[Fault]| {
+            let schedule = FaultSchedule {
+                faults: faults.to_vec(),
+            };
+            self.run_seed(seed, build, Some(schedule), failed.ran_for)
+                .result
+                .is_err()
+        };
+        // Injecting the recorded faults should reproduce the failure, unless the run depends on
+        // something the schedule doesn't capture
+        if !fails(&failed.faults.faults) {
+            return Shrunk {
+                seed,
+                run_for: failed.ran_for,
+                schedule: failed.faults,
+                message: failed.result.unwrap_err(),
+            };
+        }
+        let schedule = FaultSchedule {
+            faults: ddmin(failed.faults.faults, fails),
+        };
+        let last = self.run_seed(seed, build, Some(schedule.clone()), failed.ran_for);
+        Shrunk {
+            seed,
+            run_for: last.ran_for,
+            schedule,
+            message: last.result.unwrap_err(),
+        }
+    }
+
+    /// Builds and runs one simulation for `run_for`, with exactly the faults of `schedule` if
+    /// there is one.
+    fn run_seed<H, F>(&self, seed: u64, build: &F, schedule: Option<FaultSchedule>, run_for: std::time::Duration) -> Run
+    where
+        H: PublishHook,
+        F: Fn(u64) -> Simulator<H>,
+    {
+        let mut simulator = None;
+        let mut start = SimTime::EPOCH;
+        let result = quietly(|| std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut built = build(seed).with_fault_recording();
+            if let Some(schedule) = schedule {
+                built = built.with_fault_schedule(schedule);
+            }
+            start = built.time();
+            let simulator = simulator.insert(built);
+            match self.step_by {
+                Some(step_by) => simulator.step_to(start + run_for, step_by),
+                None => simulator.run_until(start + run_for),
+            };
+        })))
+        .map_err(|panic| {
+            panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|message| message.to_string()))
+                .unwrap_or_else(|| "panicked".to_string())
+        });
+        // A failing step leaves the time at the end of that step
+        let (faults, ran_for) = simulator
+            .map(|simulator| (simulator.fault_schedule(), simulator.time() - start))
+            .unwrap_or_default();
+        Run {
+            result,
+            faults,
+            ran_for,
+        }
+    }
+}
+
+thread_local! {
+    /// Set while the current thread runs a simulation that is expected to panic
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` without printing the panics it catches, which a sweep reports instead. Panics on other
+/// threads still go to the previous panic hook.
+fn quietly<T>(f: impl FnOnce() -> T) -> T {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !QUIET.with(Cell::get) {
+                previous(info);
+            }
+        }));
+    });
+    QUIET.with(|quiet| quiet.set(true));
+    let result = f();
+    QUIET.with(|quiet| quiet.set(false));
+    result
+}
+
+/// The outcome of a single simulation run.
+struct Run {
+    result: Result<(), String>,
+    /// The faults injected into the run
+    faults: FaultSchedule,
+    /// How long the run went on for, up to the failure if it failed
+    ran_for: std::time::Duration,
+}
+
+/// Delta debugging: reduces `items` to a smaller subset for which `fails` still holds, removing
+/// chunks of them and then single items until nothing more can be removed. `fails(items)` must
+/// hold to begin with.
+fn ddmin<T: Clone>(mut items: Vec<T>, mut fails: impl FnMut(&[T]) -> bool) -> Vec<T> {
+    if fails(&[]) {
+        return vec![];
+    }
+    let mut chunks = 2;
+    while items.len() >= 2 {
+        let size = items.len().div_ceil(chunks);
+        let parts: Vec<&[T]> = items.chunks(size).collect();
+        let mut reduced = None;
+        for part in parts.iter() {
+            if fails(part) {
+                reduced = Some((part.to_vec(), 2));
+                break;
+            }
+        }
+        if reduced.is_none() && parts.len() > 2 {
+            for i in 0..parts.len() {
+                let complement: Vec<T> = parts
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .flat_map(|(_, part)| part.iter().cloned())
+                    .collect();
+                if fails(&complement) {
+                    reduced = Some((complement, (chunks - 1).max(2)));
+                    break;
+                }
+            }
+        }
+        match reduced {
+            Some((subset, next_chunks)) => {
+                items = subset;
+                chunks = next_chunks;
+            }
+            None if chunks >= items.len() => break,
+            None => chunks = (chunks * 2).min(items.len()),
+        }
+    }
+    items
+}
+
+/// Runs `build(seed)` for every seed for `run_for` of virtual time, in parallel, see
+/// [Sweep::run]. Call [SweepReport::assert_ok] on the result to fail a test with the failing
+/// seeds.
+///
+/// The [SEED_ENV] environment variable restricts it to a single seed, see [Sweep::from_env].
+pub fn sweep<H, F>(seeds: impl IntoIterator<Item = u64>, run_for: std::time::Duration, build: F) -> SweepReport
+where
+    H: PublishHook,
+    F: Fn(u64) -> Simulator<H> + Sync,
+{
+    Sweep::new(run_for).from_env().run(seeds, build)
+}
+
+/// The command that runs a sweep again for just `seed`.
+fn reproduce(seed: u64, test: Option<&str>) -> String {
+    match test {
+        Some(test) => format!("{}={} cargo test {} -- --exact", SEED_ENV, seed, test),
+        None => format!("{}={} cargo test", SEED_ENV, seed),
+    }
+}
+
+/// A seed whose simulation panicked.
+#[derive(Debug, Clone)]
+pub struct SweepFailure {
+    pub seed: u64,
+    /// The panic message
+    pub message: String,
+    /// A command that runs the failing test again for just this seed, for sweeps that read the
+    /// seed from the environment (see [Sweep::from_env])
+    pub reproduce: Option<String>,
+    /// The minimized failure, unless shrinking is turned off
+    pub shrunk: Option<Shrunk>,
+}
+
+/// A failing seed minimized by [Sweep::shrink]: the shortest run with the fewest faults that
+/// still fails.
+#[derive(Debug, Clone)]
+pub struct Shrunk {
+    pub seed: u64,
+    /// How long the shrunk run goes on for before failing
+    pub run_for: std::time::Duration,
+    /// The remaining faults, inject them with [Simulator::with_fault_schedule]
+    pub schedule: FaultSchedule,
+    /// The panic message of the shrunk run
+    pub message: String,
+}
+
+/// The outcome of a [Sweep].
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct SweepReport {
+    /// How many seeds ran
+    pub seeds: usize,
+    /// Failing seeds, in seed order
+    pub failures: Vec<SweepFailure>,
+}
+
+impl SweepReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The failing seeds, in order.
+    pub fn failed_seeds(&self) -> Vec<u64> {
+        self.failures.iter().map(|failure| failure.seed).collect()
+    }
+
+    /// Panics listing every failing seed along with the first line of its panic message and its
+    /// reproduction command, if it has one.
+    pub fn assert_ok(&self) {
+        if !self.is_ok() {
+            panic!("{}", self);
+        }
+    }
+}
+
+impl std::fmt::Display for SweepReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_ok() {
+            return write!(f, "all {} seeds passed", self.seeds);
+        }
+        write!(f, "{} of {} seeds failed:", self.failures.len(), self.seeds)?;
+        for failure in self.failures.iter() {
+            let message = failure.message.lines().next().unwrap_or_default();
+            write!(f, "\n  seed {}: {}", failure.seed, message)?;
+            if let Some(reproduce) = &failure.reproduce {
+                write!(f, "\n    {}", reproduce)?;
+            }
+            if let Some(shrunk) = &failure.shrunk {
+                write!(
+                    f,
+                    "\n    shrunk to {:?} with {} faults",
+                    shrunk.run_for,
+                    shrunk.schedule.faults.len()
+                )?;
+                for fault in shrunk.schedule.faults.iter() {
+                    write!(f, "\n      {}", fault)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1 +1,4 @@
+pub mod harness;
 pub mod message_bus;
+
+pub use harness::*;
@@ -1,18 +1,172 @@
-fn main() {
-    println!("Hello, world!");
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::process::ExitCode;
+
+use dsim::message_bus::{MessageCodecs, SimTime, Trace, TraceEvent, TraceFilter};
+
+const USAGE: &str = "\
+Usage: dsim <command> [arguments]
+
+Commands:
+  print <trace> [filters]           Print the events of a trace, one per line
+  step <trace> [filters] [--step]   Walk through the recorded events one virtual time at a time
+                                    (waiting for enter between them with --step), then list the
+                                    envelopes that were never delivered or dropped. Subscribers
+                                    are not re-executed, see Simulator::replay for that
+  diff <trace> <trace>              Show the first event where two traces diverge
+  summary <trace>                   Count events, drops, and envelopes per link
+
+Filters:
+  --destination <name>              Events of a single subscriber
+  --type <message type>             Envelopes whose message type contains this
+  --from <time>, --to <time>        Events in a time range, e.g. 1.5s or 1500ms
+
+Traces can be in either format written by Trace::write_to.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("dsim: {}\n\n{}", error, USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<ExitCode, String> {
+    let Some((command, args)) = args.split_first() else {
+        return Err("missing command".to_string());
+    };
+    let options = Options::parse(args)?;
+    match (command.as_str(), &options.paths[..]) {
+        ("print", [path]) => {
+            let trace = read_trace(path)?;
+            for event in options.filter.apply(&trace) {
+                println!("{}", event);
+            }
+        }
+        ("step", [path]) => step(&read_trace(path)?, &options),
+        ("diff", [a, b]) => return Ok(diff(&read_trace(a)?, &read_trace(b)?)),
+        ("summary", [path]) => print!("{}", read_trace(path)?.summary()),
+        ("help", []) => println!("{}", USAGE),
+        ("print" | "step" | "diff" | "summary" | "help", _) => {
+            return Err(format!("wrong number of arguments for {}", command));
+        }
+        _ => return Err(format!("unknown command {:?}", command)),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Positional arguments and flags of a command.
+#[derive(Default)]
+struct Options {
+    paths: Vec<String>,
+    filter: TraceFilter,
+    step: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("missing value for {}", arg));
+            let time = |value: &str| value.parse::<SimTime>();
+            options.filter = match arg.as_str() {
+                "--destination" => options.filter.with_destination(value()?),
+                "--type" => options.filter.with_message_type(value()?),
+                "--from" => options.filter.with_from(time(value()?)?),
+                "--to" => options.filter.with_to(time(value()?)?),
+                "--step" => {
+                    options.step = true;
+                    continue;
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown flag {}", flag)),
+                path => {
+                    options.paths.push(path.to_string());
+                    continue;
+                }
+            };
+        }
+        Ok(options)
+    }
+}
+
+fn read_trace(path: &str) -> Result<Trace, String> {
+    let file = std::fs::File::open(path).map_err(|err| format!("{}: {}", path, err))?;
+    Trace::read_from(file, &MessageCodecs::new()).map_err(|err| format!("{}: {}", path, err))
+}
+
+/// Prints the recorded events, it can't replay them: the binary has no subscribers to feed them to.
+fn step(trace: &Trace, options: &Options) {
+    let mut time = None;
+    for event in options.filter.apply(trace) {
+        if time.is_some_and(|time| time != event.at()) && options.step {
+            let _ = std::io::stdin().lock().read_line(&mut String::new());
+        }
+        time = Some(event.at());
+        println!("{}", event);
+    }
+
+    let settled: HashSet<u64> = trace
+        .events
+        .iter()
+        .filter_map(|event| match event {
+            TraceEvent::Deliver { id, .. } | TraceEvent::Drop { id, .. } => Some(*id),
+            _ => None,
+        })
+        .collect();
+    let in_flight: Vec<&TraceEvent> = trace
+        .events
+        .iter()
+        .filter(|event| matches!(event, TraceEvent::Publish { id, .. } if !settled.contains(id)))
+        .collect();
+    println!("\n{} envelopes never delivered or dropped", in_flight.len());
+    for event in in_flight {
+        println!("  {}", event);
+    }
+}
+
+/// Prints the first divergence with a few events of shared context, exiting with 1 like diff(1)
+/// if there is one.
+fn diff(a: &Trace, b: &Trace) -> ExitCode {
+    let Some(i) = a.first_divergence(b) else {
+        println!("traces are the same ({} events)", a.events.len());
+        return ExitCode::SUCCESS;
+    };
+    println!("traces diverge at event {}", i);
+    for event in a.events[i.saturating_sub(3)..i].iter() {
+        println!("  {}", event);
+    }
+    let line = |trace: &Trace| {
+        trace
+            .events
+            .get(i)
+            .map_or("(end of trace)".to_string(), |event| event.to_string())
+    };
+    println!("- {}", line(a));
+    println!("+ {}", line(b));
+    ExitCode::from(1)
 }
 
 #[cfg(test)]
 mod tests {
-    use dsim::message_bus::{Message, MessageBus, Simulator, Subscriber};
+    use dsim::{Sweep, sweep};
+    use dsim::message_bus::{
+        Clock, Context, DeadLetter, DeadLetterBuffer, DeadLetters, DropReason, Envelope, Fault, FaultRates, FixedLatency, Message, MessageBus, NetworkFaults,
+        MessageCodecs, PublishHook, RandomClocks, Recorder, Scenario, SerializedMessage, SimAction, SimTime, Simulator,
+        SimulatorEvent, Subscriber, SubscriberView, Trace, TraceEvent, TraceFilter, TraceFormat, UnknownDestination,
+    };
     use std::{
-        collections::VecDeque,
-        time::{self, UNIX_EPOCH},
+        collections::{HashMap, VecDeque},
+        sync::{Arc, Mutex},
+        time,
     };
 
     /// PingPong will emit a ping every tick, and respond with a pong.
     struct PingPong {
-        pings: VecDeque<std::time::SystemTime>,
+        pings: VecDeque<SimTime>,
         ping_hold_time: std::time::Duration,
         destination: String,
         name: String,
@@ -32,10 +186,13 @@ mod tests {
     }
 
     impl Subscriber for PingPong {
+        #[allow(clippy::redundant_pattern_matching)]
         fn receive(
             &mut self,
+            _ctx: &mut Context,
             msg: Box<dyn Message>,
-            at: std::time::SystemTime,
+            _source: &str,
+            at: SimTime,
         ) -> Vec<dsim::message_bus::Envelope> {
             if let Some(_) = msg.downcast_ref::<Ping>() {
                 self.pings.push_back(at);
@@ -48,21 +205,14 @@ mod tests {
             vec![]
         }
 
-        fn tick(&mut self, at: std::time::SystemTime) -> Vec<dsim::message_bus::Envelope> {
-            let mut out: Vec<dsim::message_bus::Envelope> = vec![dsim::message_bus::Envelope {
-                message: Box::new(Ping {}),
-                destination: self.destination.clone(),
-                priority: self.priority,
-            }];
+        fn tick(&mut self, _ctx: &mut Context, at: SimTime) -> Vec<dsim::message_bus::Envelope> {
+            let mut out: Vec<dsim::message_bus::Envelope> =
+                vec![Envelope::new(&self.destination, Ping {}).with_priority(self.priority)];
             while let Some(&oldest) = self.pings.front() {
-                if at.duration_since(oldest).unwrap() >= self.ping_hold_time {
+                if at.duration_since(oldest) >= self.ping_hold_time {
                     self.pings.pop_front();
                     println!("{} sending pong to {}", self.name, self.destination);
-                    out.push(dsim::message_bus::Envelope {
-                        message: Box::new(Pong {}),
-                        destination: self.destination.clone(),
-                        priority: self.priority,
-                    });
+                    out.push(Envelope::new(&self.destination, Pong {}).with_priority(self.priority));
                 } else {
                     break;
                 }
@@ -73,12 +223,698 @@ mod tests {
 
     struct Ping {}
 
-    impl Message for Ping {}
+    impl Message for Ping {
+        fn try_clone(&self) -> Option<Box<dyn Message>> {
+            Some(Box::new(Ping {}))
+        }
+
+        fn to_serialized(&self) -> Option<SerializedMessage> {
+            Some(SerializedMessage::new("Ping", ""))
+        }
+    }
 
     struct Pong {}
 
     impl Message for Pong {}
 
+    /// Records the destination of every published envelope, in publish order.
+    #[derive(Clone, Default)]
+    struct DestinationRecorder {
+        destinations: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl PublishHook for DestinationRecorder {
+        fn on_publish(&self, envelope: &Envelope, _at: SimTime) {
+            self.destinations.lock().unwrap().push(envelope.destination.clone());
+        }
+    }
+
+    fn ping_pong_ring(size: usize) -> HashMap<String, Box<dyn Subscriber>> {
+        (0..size)
+            .map(|i| {
+                let name = format!("node_{}", i);
+                let next = format!("node_{}", (i + 1) % size);
+                let node = PingPong::new(std::time::Duration::from_millis(300), &next, &name, 0);
+                (name, Box::new(node) as Box<dyn Subscriber>)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_simulator_deterministic_order() {
+        let run = || {
+            let recorder = DestinationRecorder::default();
+            let mut simulator =
+                Simulator::with_hook(ping_pong_ring(8), SimTime::EPOCH, vec![vec![]], recorder.clone());
+            simulator.step_to(SimTime::EPOCH + std::time::Duration::from_secs(2), std::time::Duration::from_millis(100));
+            recorder.destinations.lock().unwrap().clone()
+        };
+
+        let first = run();
+        // Every map has a different random iteration order, but the traces must match
+        for _ in 0..5 {
+            assert_eq!(first, run());
+        }
+        // Sorted order: node_0 ticks first and pings node_1, node_7 ticks last and pings node_0
+        assert_eq!(first[0], "node_1");
+        assert_eq!(first[7], "node_0");
+    }
+
+    /// Draws a random number from its context on every tick.
+    struct Dice {
+        rolls: Arc<Mutex<Vec<(String, u64)>>>,
+    }
+
+    impl Subscriber for Dice {
+        fn receive(
+            &mut self,
+            _ctx: &mut Context,
+            _msg: Box<dyn Message>,
+            _source: &str,
+            _at: SimTime,
+        ) -> Vec<Envelope> {
+            vec![]
+        }
+
+        fn tick(&mut self, ctx: &mut Context, _at: SimTime) -> Vec<Envelope> {
+            let roll = ctx.rng().gen_range(0, 1_000_000);
+            self.rolls.lock().unwrap().push((ctx.name().to_string(), roll));
+            vec![]
+        }
+    }
+
+    #[test]
+    fn test_simulator_seeded_rng() {
+        let run = |seed: u64| {
+            let rolls = Arc::new(Mutex::new(vec![]));
+            let subscribers = ["a", "b", "c"].map(|name| {
+                let dice = Dice { rolls: rolls.clone() };
+                (name.to_string(), Box::new(dice) as Box<dyn Subscriber>)
+            });
+            let mut simulator = Simulator::new(subscribers, SimTime::EPOCH, vec![]).with_seed(seed);
+            for _ in 0..10 {
+                simulator.step(std::time::Duration::from_millis(100));
+            }
+            rolls.lock().unwrap().clone()
+        };
+
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+        // Subscribers get independent streams
+        let rolls = run(7);
+        assert_ne!(rolls[0].1, rolls[1].1);
+    }
+
+    /// Records the time of every message it receives.
+    struct Sink {
+        received: Arc<Mutex<Vec<SimTime>>>,
+    }
+
+    impl Subscriber for Sink {
+        fn receive(
+            &mut self,
+            _ctx: &mut Context,
+            _msg: Box<dyn Message>,
+            _source: &str,
+            at: SimTime,
+        ) -> Vec<Envelope> {
+            self.received.lock().unwrap().push(at);
+            vec![]
+        }
+
+        fn tick(&mut self, _ctx: &mut Context, _at: SimTime) -> Vec<Envelope> {
+            vec![]
+        }
+    }
+
+    /// A pinger pinging the sink on every tick, and the sink recording them in `received`.
+    fn pinger_and_sink(received: &Arc<Mutex<Vec<SimTime>>>) -> HashMap<String, Box<dyn Subscriber>> {
+        let pinger = PingPong::new(std::time::Duration::from_secs(60), "sink", "pinger", 0);
+        let sink = Sink { received: received.clone() };
+        maplit::hashmap! {
+            "pinger".to_string() => Box::new(pinger) as Box<dyn Subscriber>,
+            "sink".to_string() => Box::new(sink) as Box<dyn Subscriber>,
+        }
+    }
+
+    #[test]
+    fn test_simulator_latency() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut simulator = Simulator::new(
+            pinger_and_sink(&received),
+            SimTime::EPOCH,
+            vec![vec![]],
+        )
+        .with_latency(FixedLatency(std::time::Duration::from_millis(250)));
+
+        // The first ping is sent at 0ms, due at 250ms, and delivered by the step starting at 300ms
+        simulator.step_to(SimTime::EPOCH + std::time::Duration::from_millis(300), std::time::Duration::from_millis(100));
+        assert!(received.lock().unwrap().is_empty());
+        simulator.step(std::time::Duration::from_millis(100));
+        assert_eq!(*received.lock().unwrap(), vec![SimTime::EPOCH + std::time::Duration::from_millis(250)]);
+    }
+
+    /// Counts the envelopes dropped by the network.
+    #[derive(Clone, Default)]
+    struct DropCounter {
+        drops: Arc<Mutex<usize>>,
+    }
+
+    impl PublishHook for DropCounter {
+        fn on_publish(&self, _envelope: &Envelope, _at: SimTime) {}
+
+        fn on_drop(&self, _envelope: &Envelope, _reason: DropReason, _at: SimTime) {
+            *self.drops.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn test_simulator_faults() {
+        let run = |faults: NetworkFaults| {
+            let received = Arc::new(Mutex::new(vec![]));
+            let counter = DropCounter::default();
+            let mut simulator = Simulator::with_hook(
+                pinger_and_sink(&received),
+                SimTime::EPOCH,
+                vec![vec![]],
+                counter.clone(),
+            )
+            .with_faults(faults);
+            for _ in 0..10 {
+                simulator.step(std::time::Duration::from_millis(100));
+            }
+            let received = received.lock().unwrap().len();
+            let drops = *counter.drops.lock().unwrap();
+            (received, drops)
+        };
+
+        // 10 pings sent, the last one is still waiting for the next step
+        let none = FaultRates::default();
+        assert_eq!(run(NetworkFaults::new(none)), (9, 0));
+        let drop_pings = NetworkFaults::new(none).with_message::<Ping>(FaultRates { drop: 1.0, ..none });
+        assert_eq!(run(drop_pings), (0, 10));
+        let duplicate_link = NetworkFaults::new(none).with_link("pinger", "sink", FaultRates { duplicate: 1.0, ..none });
+        assert_eq!(run(duplicate_link), (18, 0));
+        let flaky = NetworkFaults::new(FaultRates { drop: 0.5, ..none });
+        let (received, drops) = run(flaky.clone());
+        assert!(drops > 0 && received > 0);
+        assert_eq!(run(flaky), (received, drops));
+
+        // Reordered pings overtake each other, but every one of them still arrives
+        let recorder = Recorder::new();
+        let ms = std::time::Duration::from_millis;
+        let mut simulator = Simulator::with_hook(
+            pinger_and_sink(&Arc::default()),
+            SimTime::EPOCH,
+            vec![vec![]],
+            recorder.clone(),
+        )
+        .with_faults(NetworkFaults::new(FaultRates { reorder: 0.5, ..none }).with_reorder_delay(ms(500)));
+        simulator.step_to(SimTime::EPOCH + ms(1000), ms(100));
+        simulator.crash("pinger");
+        simulator.step_to(SimTime::EPOCH + ms(2000), ms(100));
+        let trace = recorder.take();
+        let published: Vec<u64> = trace
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Publish { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect();
+        let delivered: Vec<u64> = trace
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Deliver { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(published.len(), 10);
+        assert_ne!(delivered, published);
+        let mut sorted = delivered.clone();
+        sorted.sort();
+        assert_eq!(sorted, published);
+    }
+
+    #[test]
+    fn test_simulator_partitions() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let counter = DropCounter::default();
+        let mut simulator = Simulator::with_hook(
+            pinger_and_sink(&received),
+            SimTime::EPOCH,
+            vec![vec![]],
+            counter.clone(),
+        );
+        let ms = std::time::Duration::from_millis;
+        simulator.schedule(
+            SimTime::EPOCH + ms(300),
+            SimAction::Partition(vec![vec!["pinger".to_string()], vec!["sink".to_string()]]),
+        );
+        simulator.schedule(SimTime::EPOCH + ms(600), SimAction::Heal);
+
+        // Pings sent at 300, 400 and 500ms are dropped, and so is the ping sent at 200ms that was
+        // still queued when the partition started
+        simulator.step_to(SimTime::EPOCH + ms(1000), ms(100));
+        assert_eq!(*counter.drops.lock().unwrap(), 4);
+        assert_eq!(received.lock().unwrap().len(), 5);
+
+        // Only the pinger -> sink direction is cut, the ping queued at 900ms included
+        simulator.cut_link("pinger", "sink");
+        simulator.step_to(SimTime::EPOCH + ms(1500), ms(100));
+        assert_eq!(*counter.drops.lock().unwrap(), 10);
+        simulator.restore_link("pinger", "sink");
+        simulator.step_to(SimTime::EPOCH + ms(2000), ms(100));
+        assert_eq!(*counter.drops.lock().unwrap(), 10);
+
+        // Cutting the reverse direction doesn't stop pings to the sink
+        let before = received.lock().unwrap().len();
+        simulator.cut_link("sink", "pinger");
+        simulator.step_to(SimTime::EPOCH + ms(2500), ms(100));
+        assert_eq!(*counter.drops.lock().unwrap(), 10);
+        assert_eq!(received.lock().unwrap().len(), before + 5);
+    }
+
+    /// Replies to every message with a Pong to whoever sent it.
+    struct Echo;
+
+    impl Subscriber for Echo {
+        fn receive(
+            &mut self,
+            _ctx: &mut Context,
+            _msg: Box<dyn Message>,
+            source: &str,
+            _at: SimTime,
+        ) -> Vec<Envelope> {
+            vec![Envelope::new(source, Pong {})]
+        }
+
+        fn tick(&mut self, _ctx: &mut Context, _at: SimTime) -> Vec<Envelope> {
+            vec![]
+        }
+    }
+
+    /// Records the (source, destination) of every published envelope.
+    #[derive(Clone, Default)]
+    struct LinkRecorder {
+        links: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl PublishHook for LinkRecorder {
+        fn on_publish(&self, envelope: &Envelope, _at: SimTime) {
+            let link = (envelope.source.clone(), envelope.destination.clone());
+            self.links.lock().unwrap().push(link);
+        }
+    }
+
+    #[test]
+    fn test_simulator_source() {
+        let recorder = LinkRecorder::default();
+        let pinger = PingPong::new(std::time::Duration::from_secs(60), "echo", "pinger", 0);
+        let mut simulator = Simulator::with_hook(
+            maplit::hashmap! {
+                "pinger".to_string() => Box::new(pinger) as Box<dyn Subscriber>,
+                "echo".to_string() => Box::new(Echo) as Box<dyn Subscriber>,
+            },
+            SimTime::EPOCH,
+            vec![vec![]],
+            recorder.clone(),
+        );
+        simulator.step(std::time::Duration::from_millis(100));
+        simulator.step(std::time::Duration::from_millis(100));
+
+        let links = recorder.links.lock().unwrap().clone();
+        let link = |source: &str, destination: &str| (source.to_string(), destination.to_string());
+        assert_eq!(links, vec![link("pinger", "echo"), link("pinger", "echo"), link("echo", "pinger")]);
+    }
+
+    #[test]
+    fn test_simulator_crash_restart() {
+        let before = Arc::new(Mutex::new(vec![]));
+        let after = Arc::new(Mutex::new(vec![]));
+        let counter = DropCounter::default();
+        let mut simulator = Simulator::with_hook(
+            pinger_and_sink(&before),
+            SimTime::EPOCH,
+            vec![vec![]],
+            counter.clone(),
+        );
+        let restarted = after.clone();
+        simulator.set_restart_factory("sink", move || Box::new(Sink { received: restarted.clone() }));
+        let ms = std::time::Duration::from_millis;
+        simulator.schedule(SimTime::EPOCH + ms(300), SimAction::Crash("sink".to_string()));
+        simulator.schedule(SimTime::EPOCH + ms(600), SimAction::Restart("sink".to_string()));
+
+        simulator.step_to(SimTime::EPOCH + ms(500), ms(100));
+        assert!(simulator.is_crashed("sink"));
+        simulator.step_to(SimTime::EPOCH + ms(1000), ms(100));
+        assert!(!simulator.is_crashed("sink"));
+
+        // Pings sent at 200ms (queued), 300ms and 400ms were lost
+        assert_eq!(before.lock().unwrap().len(), 2);
+        assert_eq!(*counter.drops.lock().unwrap(), 3);
+        assert_eq!(after.lock().unwrap().len(), 4);
+    }
+
+    /// Spawns a child on its first tick and pings it.
+    struct Spawner {
+        received: Arc<Mutex<Vec<SimTime>>>,
+        spawned: bool,
+    }
+
+    impl Subscriber for Spawner {
+        fn receive(
+            &mut self,
+            _ctx: &mut Context,
+            _msg: Box<dyn Message>,
+            _source: &str,
+            _at: SimTime,
+        ) -> Vec<Envelope> {
+            vec![]
+        }
+
+        fn tick(&mut self, ctx: &mut Context, _at: SimTime) -> Vec<Envelope> {
+            if self.spawned {
+                return vec![];
+            }
+            self.spawned = true;
+            ctx.spawn("child", Box::new(Sink { received: self.received.clone() }));
+            vec![Envelope::new("child", Ping {})]
+        }
+    }
+
+    #[test]
+    fn test_simulator_dynamic_subscribers() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let spawner = Spawner { received: received.clone(), spawned: false };
+        let mut simulator = Simulator::new(
+            maplit::hashmap! { "spawner".to_string() => Box::new(spawner) as Box<dyn Subscriber> },
+            SimTime::EPOCH,
+            vec![vec![]],
+        );
+        simulator.step(std::time::Duration::from_millis(100));
+        simulator.step(std::time::Duration::from_millis(100));
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        // A pinger added later reaches the child until it is removed
+        let pinger = PingPong::new(std::time::Duration::from_secs(60), "child", "pinger", 0);
+        simulator.subscribe("pinger".to_string(), Box::new(pinger));
+        simulator.step(std::time::Duration::from_millis(100));
+        simulator.step(std::time::Duration::from_millis(100));
+        assert_eq!(received.lock().unwrap().len(), 2);
+        assert!(simulator.unsubscribe("pinger").is_some());
+        assert!(simulator.unsubscribe("pinger").is_none());
+        simulator.step(std::time::Duration::from_millis(100));
+        assert_eq!(received.lock().unwrap().len(), 3);
+        simulator.step(std::time::Duration::from_millis(100));
+        assert_eq!(received.lock().unwrap().len(), 3);
+
+        // Envelopes queued for a removed subscriber are dropped
+        let pinger = PingPong::new(std::time::Duration::from_secs(60), "child", "pinger", 0);
+        simulator.subscribe("pinger".to_string(), Box::new(pinger));
+        simulator.step(std::time::Duration::from_millis(100));
+        assert!(simulator.unsubscribe("child").is_some());
+        simulator.step(std::time::Duration::from_millis(100));
+        assert_eq!(received.lock().unwrap().len(), 3);
+
+        // A subscriber replacing a crashed one starts out running
+        simulator.subscribe("child".to_string(), Box::new(Sink { received: received.clone() }));
+        simulator.crash("child");
+        simulator.subscribe("child".to_string(), Box::new(Sink { received: received.clone() }));
+        assert!(!simulator.is_crashed("child"));
+        simulator.step(std::time::Duration::from_millis(100));
+        simulator.step(std::time::Duration::from_millis(100));
+        assert_eq!(received.lock().unwrap().len(), 4);
+
+        // Crashes scheduled for a removed subscriber don't hit the one registered after it
+        simulator.schedule(simulator.time(), SimAction::Crash("child".to_string()));
+        simulator.unsubscribe("child");
+        simulator.subscribe("child".to_string(), Box::new(Sink { received: received.clone() }));
+        simulator.step(std::time::Duration::from_millis(100));
+        assert!(!simulator.is_crashed("child"));
+    }
+
+    #[test]
+    fn test_simulator_event_driven() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut simulator = Simulator::new(
+            pinger_and_sink(&received),
+            SimTime::EPOCH,
+            vec![vec![]],
+        )
+        .with_latency(FixedLatency(std::time::Duration::from_millis(10)))
+        .with_tick_interval(std::time::Duration::from_secs(60));
+
+        // 10 hours of virtual time, one ping a minute: only the 601 ticks (including the one at
+        // the end) and 600 deliveries are visited
+        let end = SimTime::EPOCH + std::time::Duration::from_secs(10 * 3600);
+        let mut steps = 0;
+        while simulator.next_event_time().is_some_and(|next| next <= end) {
+            simulator.step_next();
+            steps += 1;
+        }
+        assert_eq!(steps, 1201);
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 600);
+        assert_eq!(received[1], SimTime::EPOCH + std::time::Duration::from_millis(60_010));
+        assert_eq!(simulator.run_until(end), end);
+
+        // Nothing happens without ticks or envelopes
+        let mut idle = Simulator::new(maplit::hashmap! {}, SimTime::EPOCH, vec![]);
+        assert_eq!(idle.step_next(), None);
+
+        // A zero tick interval would never get past the first tick
+        let zero = std::panic::catch_unwind(|| {
+            Simulator::new(maplit::hashmap! {}, SimTime::EPOCH, vec![]).with_tick_interval(std::time::Duration::ZERO)
+        });
+        assert!(zero.is_err());
+    }
+
+    /// Sets a timer on its first tick, and records when it fires.
+    struct Sleeper {
+        fired: Arc<Mutex<Vec<(u64, SimTime)>>>,
+        armed: bool,
+    }
+
+    impl Subscriber for Sleeper {
+        fn receive(
+            &mut self,
+            _ctx: &mut Context,
+            _msg: Box<dyn Message>,
+            _source: &str,
+            _at: SimTime,
+        ) -> Vec<Envelope> {
+            vec![]
+        }
+
+        fn tick(&mut self, ctx: &mut Context, at: SimTime) -> Vec<Envelope> {
+            if !self.armed {
+                self.armed = true;
+                ctx.set_timer(at + std::time::Duration::from_millis(250), 7);
+            }
+            vec![]
+        }
+
+        fn timer(&mut self, _ctx: &mut Context, token: u64, at: SimTime) -> Vec<Envelope> {
+            self.fired.lock().unwrap().push((token, at));
+            vec![]
+        }
+    }
+
+    #[test]
+    fn test_timers() {
+        let ms = std::time::Duration::from_millis;
+        let simulator = |fired: &Arc<Mutex<Vec<(u64, SimTime)>>>| {
+            let sleeper = Sleeper { fired: fired.clone(), armed: false };
+            Simulator::new(
+                maplit::hashmap! { "sleeper".to_string() => Box::new(sleeper) as Box<dyn Subscriber> },
+                SimTime::EPOCH,
+                vec![vec![]],
+            )
+        };
+
+        // Fixed steps fire the timer on the first step at or after its due time
+        let fired = Arc::new(Mutex::new(vec![]));
+        let mut stepped = simulator(&fired);
+        stepped.step_to(SimTime::EPOCH + ms(300), ms(100));
+        assert!(fired.lock().unwrap().is_empty());
+        stepped.step(ms(100));
+        assert_eq!(*fired.lock().unwrap(), vec![(7, SimTime::EPOCH + ms(250))]);
+
+        // A replaced subscriber's timers don't fire on its replacement
+        let fired = Arc::new(Mutex::new(vec![]));
+        let replaced = Arc::new(Mutex::new(vec![]));
+        let mut stepped = simulator(&replaced);
+        stepped.step(ms(100));
+        stepped.subscribe("sleeper".to_string(), Box::new(Sleeper { fired: fired.clone(), armed: true }));
+        stepped.step_to(SimTime::EPOCH + ms(500), ms(100));
+        assert!(fired.lock().unwrap().is_empty());
+        assert!(replaced.lock().unwrap().is_empty());
+
+        // Event-driven stepping jumps straight to it
+        let fired = Arc::new(Mutex::new(vec![]));
+        let mut event_driven = simulator(&fired).with_tick_interval(std::time::Duration::from_secs(3600));
+        event_driven.step_next();
+        assert_eq!(event_driven.step_next(), Some(SimTime::EPOCH + ms(250)));
+        assert_eq!(*fired.lock().unwrap(), vec![(7, SimTime::EPOCH + ms(250))]);
+
+        // The message bus fires it in real time
+        let fired = Arc::new(Mutex::new(vec![]));
+        let mut message_bus = MessageBus::new(std::time::Duration::from_secs(10), 1);
+        let sleeper = Sleeper { fired: fired.clone(), armed: false };
+        message_bus.subscribe("sleeper".to_string(), Box::new(sleeper));
+        message_bus.start();
+        wait_for(|| !fired.lock().unwrap().is_empty());
+        message_bus.stop();
+        let fired = fired.lock().unwrap();
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].0, 7);
+    }
+
+    /// Sends a single delayed Ping on its first tick.
+    struct DelayedSender {
+        sent: bool,
+    }
+
+    impl Subscriber for DelayedSender {
+        fn receive(
+            &mut self,
+            _ctx: &mut Context,
+            _msg: Box<dyn Message>,
+            _source: &str,
+            _at: SimTime,
+        ) -> Vec<Envelope> {
+            vec![]
+        }
+
+        fn tick(&mut self, _ctx: &mut Context, _at: SimTime) -> Vec<Envelope> {
+            if self.sent {
+                return vec![];
+            }
+            self.sent = true;
+            vec![Envelope::new("sink", Ping {}).with_delay(std::time::Duration::from_millis(500))]
+        }
+    }
+
+    #[test]
+    fn test_delayed_delivery() {
+        let ms = std::time::Duration::from_millis;
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut simulator = Simulator::new(
+            maplit::hashmap! {
+                "sender".to_string() => Box::new(DelayedSender { sent: false }) as Box<dyn Subscriber>,
+                "sink".to_string() => Box::new(Sink { received: received.clone() }) as Box<dyn Subscriber>,
+            },
+            SimTime::EPOCH,
+            vec![vec![]],
+        );
+        simulator.step_to(SimTime::EPOCH + ms(500), ms(100));
+        assert!(received.lock().unwrap().is_empty());
+        simulator.step(ms(100));
+        assert_eq!(*received.lock().unwrap(), vec![SimTime::EPOCH + ms(500)]);
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut message_bus = MessageBus::new(std::time::Duration::from_secs(10), 1);
+        message_bus.subscribe("sender".to_string(), Box::new(DelayedSender { sent: false }));
+        message_bus.subscribe("sink".to_string(), Box::new(Sink { received: received.clone() }));
+        let started = time::Instant::now();
+        message_bus.start();
+        wait_for(|| !received.lock().unwrap().is_empty());
+        assert!(started.elapsed() >= ms(500));
+        message_bus.stop();
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_simulator_clock_skew() {
+        let ms = std::time::Duration::from_millis;
+        let received = Arc::new(Mutex::new(vec![]));
+        let mut simulator = Simulator::new(
+            maplit::hashmap! {
+                "sender".to_string() => Box::new(DelayedSender { sent: false }) as Box<dyn Subscriber>,
+                "sink".to_string() => Box::new(Sink { received: received.clone() }) as Box<dyn Subscriber>,
+            },
+            SimTime::EPOCH,
+            vec![vec![]],
+        )
+        .with_clock("sink", Clock::ahead(ms(1000)).with_drift(0.1));
+        simulator.step_to(SimTime::EPOCH + ms(600), ms(100));
+        // Received at global 500ms, which is 500ms + 1s offset + 50ms drift on the sink's clock
+        assert_eq!(*received.lock().unwrap(), vec![SimTime::EPOCH + ms(1550)]);
+        assert_eq!(simulator.local_time("sink"), SimTime::EPOCH + ms(1660));
+        assert_eq!(simulator.local_time("sender"), SimTime::EPOCH + ms(600));
+
+        // Timers fire with exactly the local time they were set for, despite the drift
+        let fired = Arc::new(Mutex::new(vec![]));
+        let mut simulator = Simulator::new(maplit::hashmap! {}, SimTime::EPOCH, vec![vec![]])
+            .with_clock("sleeper", Clock::default().with_drift(0.5));
+        simulator.step(std::time::Duration::from_nanos(1));
+        simulator.subscribe("sleeper".to_string(), Box::new(Sleeper { fired: fired.clone(), armed: false }));
+        simulator.step_to(SimTime::EPOCH + ms(500), ms(100));
+        assert_eq!(*fired.lock().unwrap(), vec![(7, SimTime::from_nanos(250_000_001))]);
+
+        // Random clocks are reproducible from the seed, and stay within bounds
+        let clocks = RandomClocks {
+            max_offset: ms(500),
+            max_drift: 0.01,
+        };
+        let start = SimTime::EPOCH + ms(1000);
+        let local_times = |seed| {
+            let mut simulator = Simulator::new(
+                maplit::hashmap! {
+                    "a".to_string() => Box::new(Echo) as Box<dyn Subscriber>,
+                    "b".to_string() => Box::new(Echo) as Box<dyn Subscriber>,
+                },
+                start,
+                vec![vec![]],
+            )
+            .with_seed(seed)
+            .with_random_clocks(clocks);
+            simulator.step_to(start + ms(10_000), ms(1000));
+            (simulator.local_time("a"), simulator.local_time("b"))
+        };
+        let (a, b) = local_times(3);
+        assert_eq!((a, b), local_times(3));
+        assert_ne!(a, b);
+        for local in [a, b] {
+            assert!(local >= start + ms(9400) && local <= start + ms(10_600));
+        }
+
+        // A clock behind global time can't start at SimTime::EPOCH, it would have to read before it
+        let behind = |start: SimTime| {
+            Simulator::new(
+                maplit::hashmap! { "a".to_string() => Box::new(Echo) as Box<dyn Subscriber> },
+                start,
+                vec![vec![]],
+            )
+            .with_clock("a", Clock::behind(ms(1000)))
+        };
+        assert!(std::panic::catch_unwind(|| behind(SimTime::EPOCH)).is_err());
+        let mut simulator = behind(start);
+        assert_eq!(simulator.local_time("a"), SimTime::EPOCH);
+        simulator.step_to(start + ms(500), ms(100));
+        assert_eq!(simulator.local_time("a"), SimTime::EPOCH + ms(500));
+    }
+
+    #[test]
+    fn test_sim_time() {
+        let ms = std::time::Duration::from_millis;
+        let earlier = SimTime::EPOCH + ms(100);
+        let later = earlier + ms(50);
+        assert_eq!(later.duration_since(earlier), ms(50));
+        // Never fails, even when the times are the wrong way around
+        assert_eq!(earlier.duration_since(later), std::time::Duration::ZERO);
+        assert_eq!(earlier.checked_duration_since(later), None);
+        assert_eq!(earlier - ms(200), SimTime::EPOCH);
+        assert_eq!(SimTime::MAX + ms(1), SimTime::MAX);
+        assert_eq!(SimTime::from_nanos(1_500_000_000).to_string(), "1.500000000s");
+
+        let system: std::time::SystemTime = later.into();
+        assert_eq!(SimTime::from(system), later);
+        assert_eq!(SimTime::from(std::time::UNIX_EPOCH - ms(1)), SimTime::EPOCH);
+    }
+
     #[test]
     fn test_message_bus() {
         let mut message_bus = MessageBus::new(std::time::Duration::from_millis(500), 2);
@@ -101,6 +937,668 @@ mod tests {
         message_bus.stop();
     }
 
+    #[test]
+    fn test_record_replay() {
+        let ms = std::time::Duration::from_millis;
+        let subscribers = |fired: &Arc<Mutex<Vec<(u64, SimTime)>>>| {
+            let mut subscribers = ping_pong_ring(3);
+            let sleeper = Sleeper { fired: fired.clone(), armed: false };
+            subscribers.insert("sleeper".to_string(), Box::new(sleeper));
+            subscribers
+        };
+
+        // Record a real time run, including an envelope published from outside
+        let recorder = Recorder::new();
+        let recorded_fired = Arc::new(Mutex::new(vec![]));
+        let mut message_bus = MessageBus::with_hook(ms(50), 1, recorder.clone()).with_seed(5);
+        for (destination, subscriber) in subscribers(&recorded_fired) {
+            message_bus.subscribe(destination, subscriber);
+        }
+        message_bus.start();
+        std::thread::sleep(ms(100));
+        message_bus.publish(Envelope::new("node_0", Ping {}));
+        std::thread::sleep(ms(400));
+        message_bus.stop();
+        let trace = recorder.take();
+        let recorded: Vec<String> = trace.events.iter().map(|event| format!("{:?}", event)).collect();
+        assert!(recorded.iter().any(|event| event.starts_with("Deliver")));
+        assert!(recorded.iter().any(|event| event.starts_with("Publish") && event.contains("source: \"\"")));
+
+        // Replaying it reproduces the exact same run
+        let replayed = Recorder::new();
+        let replayed_fired = Arc::new(Mutex::new(vec![]));
+        let mut simulator =
+            Simulator::with_hook(subscribers(&replayed_fired), SimTime::EPOCH, vec![vec![]], replayed.clone())
+                .with_seed(5);
+        simulator.replay(trace);
+        let replayed: Vec<String> = replayed.take().events.iter().map(|event| format!("{:?}", event)).collect();
+        assert_eq!(recorded, replayed);
+        assert_eq!(*recorded_fired.lock().unwrap(), *replayed_fired.lock().unwrap());
+    }
+
+    #[test]
+    fn test_trace_file() {
+        let ms = std::time::Duration::from_millis;
+        let simulator = |recorder: &Recorder| {
+            let initial = vec![SimulatorEvent::Envelope(Envelope::new("node_0", Ping {}), SimTime::EPOCH)];
+            let mut simulator = Simulator::with_hook(ping_pong_ring(3), SimTime::EPOCH, vec![initial], recorder.clone())
+                .with_latency(FixedLatency(ms(50)));
+            simulator.schedule(SimTime::EPOCH + ms(300), SimAction::Crash("node_1".to_string()));
+            simulator.schedule(SimTime::EPOCH + ms(600), SimAction::Restart("node_1".to_string()));
+            simulator
+        };
+        let recorder = Recorder::new();
+        simulator(&recorder).step_to(SimTime::EPOCH + ms(1000), ms(100));
+        let trace = recorder.take();
+        let recorded: Vec<String> = trace.events.iter().map(|event| format!("{:?}", event)).collect();
+        assert!(recorded.iter().any(|event| event.starts_with("Crash")));
+
+        let codecs = MessageCodecs::new().with_decoder("Ping", |_| Some(Box::new(Ping {}) as Box<dyn Message>));
+        for format in [TraceFormat::JsonLines, TraceFormat::Binary] {
+            let mut file = vec![];
+            trace.write_to(&mut file, format).unwrap();
+            let read = Trace::read_from(file.as_slice(), &codecs).unwrap();
+            let read_events: Vec<String> = read.events.iter().map(|event| format!("{:?}", event)).collect();
+            assert_eq!(recorded, read_events);
+
+            // A trace loaded from a file replays like the original run
+            let replayed = Recorder::new();
+            let mut replay = Simulator::with_hook(ping_pong_ring(3), SimTime::EPOCH, vec![vec![]], replayed.clone());
+            replay.replay(read);
+            let replayed: Vec<String> = replayed.take().events.iter().map(|event| format!("{:?}", event)).collect();
+            assert_eq!(recorded, replayed);
+        }
+
+        // Both formats are detected even when the reader returns a byte at a time, like a pipe may
+        for format in [TraceFormat::JsonLines, TraceFormat::Binary] {
+            let mut file = vec![];
+            trace.write_to(&mut file, format).unwrap();
+            let read = Trace::read_from(Trickle(file.as_slice()), &codecs).unwrap();
+            assert_eq!(read.events.len(), trace.events.len());
+        }
+
+        let future = "{\"dsim_trace\":999}\n";
+        assert!(Trace::read_from(future.as_bytes(), &codecs).is_err());
+
+        // Control and non-BMP characters survive both formats
+        let recorder = Recorder::new();
+        let mut envelope = Envelope::new("n\u{8}\u{c}\u{1}/\"\\\n😀", Vote { term: 3 });
+        envelope.source = "\u{1f}𝄞".to_string();
+        recorder.on_publish(&envelope, SimTime::EPOCH);
+        let trace = recorder.take();
+        for format in [TraceFormat::JsonLines, TraceFormat::Binary] {
+            let mut file = vec![];
+            trace.write_to(&mut file, format).unwrap();
+            let read = Trace::read_from(file.as_slice(), &codecs).unwrap();
+            assert_eq!(format!("{:?}", trace.events), format!("{:?}", read.events));
+        }
+        // Including every escape other JSON writers may use
+        let escaped = format!(
+            "{{\"dsim_trace\":{}}}\n{{\"event\":\"crash\",\"destination\":\"\\b\\f\\/\\ud83d\\ude00\",\"at\":0}}\n",
+            dsim::message_bus::TRACE_FORMAT_VERSION
+        );
+        let read = Trace::read_from(escaped.as_bytes(), &codecs).unwrap();
+        assert!(matches!(&read.events[0], TraceEvent::Crash { destination, .. } if destination == "\u{8}\u{c}/😀"));
+
+        // A corrupt string length fails instead of allocating it
+        let mut file = vec![];
+        Trace { events: vec![TraceEvent::Crash { destination: "a".to_string(), at: SimTime::EPOCH }] }
+            .write_to(&mut file, TraceFormat::Binary)
+            .unwrap();
+        file[13..17].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Trace::read_from(file.as_slice(), &codecs).is_err());
+    }
+
+    /// Reads at most one byte at a time.
+    struct Trickle<R>(R);
+
+    impl<R: std::io::Read> std::io::Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    /// A message serialized with serde, by hand since serde is used without its derive macros.
+    #[cfg(feature = "serde")]
+    struct Ballot {
+        term: u64,
+    }
+
+    #[cfg(feature = "serde")]
+    impl serde::Serialize for Ballot {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.term.serialize(serializer)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl<'de> serde::Deserialize<'de> for Ballot {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            u64::deserialize(deserializer).map(|term| Ballot { term })
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    impl Message for Ballot {
+        fn to_serialized(&self) -> Option<SerializedMessage> {
+            SerializedMessage::json("Ballot", self)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_trace_file_serde() {
+        let recorder = Recorder::new();
+        recorder.on_publish(&Envelope::new("node", Ballot { term: 7 }), SimTime::EPOCH);
+        let trace = recorder.take();
+        let codecs = MessageCodecs::new().with_json::<Ballot>("Ballot");
+        for format in [TraceFormat::JsonLines, TraceFormat::Binary] {
+            let mut file = vec![];
+            trace.write_to(&mut file, format).unwrap();
+            let mut read = Trace::read_from(file.as_slice(), &codecs).unwrap();
+            let TraceEvent::Publish { payload, message, .. } = read.events.remove(0) else {
+                panic!("expected a publish event");
+            };
+            assert_eq!(payload, Some(SerializedMessage::new("Ballot", "7")));
+            assert_eq!(message.unwrap().downcast::<Ballot>().unwrap().term, 7);
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct Vote {
+        term: u64,
+    }
+
+    dsim::debug_message!(Vote, clone);
+
+    #[test]
+    fn test_message_describe() {
+        let envelope = Envelope::new("b", Vote { term: 3 });
+        assert_eq!(envelope.message.describe().as_deref(), Some("Vote { term: 3 }"));
+        let copy = envelope.message.try_clone().unwrap().downcast::<Vote>().unwrap();
+        assert_eq!(copy.term, 3);
+        assert!(format!("{:?}", envelope).contains("message: Vote { term: 3 }"));
+        // Messages that don't describe themselves show their type
+        let pong: Box<dyn Message> = Box::new(Pong {});
+        assert!(pong.type_name().ends_with("Pong"));
+        assert_eq!(format!("{:?}", pong), pong.type_name());
+
+        let recorder = Recorder::new();
+        recorder.on_publish(&envelope, SimTime::EPOCH);
+        let recorded = format!("{:?}", recorder.take().events[0]);
+        assert!(recorded.contains("description: Some(\"Vote { term: 3 }\")"));
+    }
+
+    /// Counts step starts and ends.
+    #[derive(Clone, Default)]
+    struct StepCounter {
+        steps: Arc<Mutex<(usize, usize)>>,
+    }
+
+    impl PublishHook for StepCounter {
+        fn on_publish(&self, _envelope: &Envelope, _at: SimTime) {}
+
+        fn on_step_start(&self, _at: SimTime) {
+            self.steps.lock().unwrap().0 += 1;
+        }
+
+        fn on_step_end(&self, _at: SimTime) {
+            self.steps.lock().unwrap().1 += 1;
+        }
+    }
+
+    /// Waits until `done` holds, for tests of the [MessageBus] which runs on real time. Fails after
+    /// a generous timeout instead of hanging.
+    fn wait_for(mut done: impl FnMut() -> bool) {
+        let deadline = time::Instant::now() + time::Duration::from_secs(10);
+        while !done() {
+            assert!(time::Instant::now() < deadline, "timed out waiting for the MessageBus");
+            std::thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_hooks() {
+        let ms = std::time::Duration::from_millis;
+        let counter = StepCounter::default();
+        let recorder = Recorder::new();
+        let mut simulator = Simulator::with_hook(
+            ping_pong_ring(2),
+            SimTime::EPOCH,
+            vec![vec![]],
+            (counter.clone(), recorder.clone()),
+        );
+        simulator.step_to(SimTime::EPOCH + ms(500), ms(100));
+        assert_eq!(*counter.steps.lock().unwrap(), (5, 5));
+        let events = recorder.take().events;
+        assert_eq!(events.iter().filter(|event| matches!(event, TraceEvent::Tick { .. })).count(), 5);
+        assert!(events.iter().any(|event| matches!(event, TraceEvent::Deliver { .. })));
+
+        // The MessageBus reports envelopes to unknown destinations as dropped
+        let counter = StepCounter::default();
+        let drops = DropCounter::default();
+        let mut message_bus = MessageBus::with_hook(ms(50), 1, ((counter.clone(), recorder.clone()), drops.clone()));
+        message_bus.subscribe("sink".to_string(), Box::new(Sink { received: Arc::new(Mutex::new(vec![])) }));
+        message_bus.start();
+        message_bus.publish(Envelope::new("nobody", Ping {}));
+        wait_for(|| *drops.drops.lock().unwrap() > 0 && counter.steps.lock().unwrap().1 > 0);
+        message_bus.stop();
+        let events = recorder.take().events;
+        assert!(events.iter().any(|event| matches!(
+            event,
+            TraceEvent::Drop { destination, reason: DropReason::UnknownDestination, .. } if destination == "nobody"
+        )));
+        let (starts, ends) = *counter.steps.lock().unwrap();
+        assert!(starts > 0);
+        assert_eq!(starts, ends);
+    }
+
+    /// Records the original destination of every dead letter it receives.
+    struct DeadLetterSink {
+        letters: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Subscriber for DeadLetterSink {
+        fn receive(&mut self, _ctx: &mut Context, msg: Box<dyn Message>, _source: &str, _at: SimTime) -> Vec<Envelope> {
+            let letter = msg.downcast::<DeadLetter>().unwrap();
+            self.letters.lock().unwrap().push(letter.destination);
+            vec![]
+        }
+
+        fn tick(&mut self, _ctx: &mut Context, _at: SimTime) -> Vec<Envelope> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn test_unknown_destination() {
+        let ms = std::time::Duration::from_millis;
+        let simulator = |dead_letters: DeadLetters, letters: &Arc<Mutex<Vec<String>>>, counter: &DropCounter| {
+            let initial = vec![SimulatorEvent::Envelope(Envelope::new("nobody", Ping {}), SimTime::EPOCH)];
+            Simulator::with_hook(
+                maplit::hashmap! {
+                    "dead".to_string() => Box::new(DeadLetterSink { letters: letters.clone() }) as Box<dyn Subscriber>,
+                },
+                SimTime::EPOCH,
+                vec![initial],
+                counter.clone(),
+            )
+            .with_unknown_destination(UnknownDestination::Drop)
+            .with_dead_letters(dead_letters)
+        };
+
+        // Dropped and reported
+        let letters = Arc::new(Mutex::new(vec![]));
+        let counter = DropCounter::default();
+        simulator(DeadLetters::Discard, &letters, &counter).step(ms(100));
+        assert_eq!(*counter.drops.lock().unwrap(), 1);
+        assert!(letters.lock().unwrap().is_empty());
+
+        // Or delivered to a dead-letter subscriber
+        let counter = DropCounter::default();
+        simulator(DeadLetters::Destination("dead".to_string()), &letters, &counter).step(ms(100));
+        assert_eq!(*counter.drops.lock().unwrap(), 1);
+        assert_eq!(*letters.lock().unwrap(), vec!["nobody".to_string()]);
+
+        // The simulator panics by default
+        let initial = vec![SimulatorEvent::Envelope(Envelope::new("nobody", Ping {}), SimTime::EPOCH)];
+        let mut panicking = Simulator::new(maplit::hashmap! {}, SimTime::EPOCH, vec![initial]);
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| panicking.step(ms(100)))).unwrap_err();
+        assert!(panic.downcast_ref::<String>().unwrap().contains("destination: \"nobody\""));
+
+        // The MessageBus follows the same policy
+        let letters = Arc::new(Mutex::new(vec![]));
+        let mut message_bus =
+            MessageBus::new(ms(50), 1).with_dead_letters(DeadLetters::Destination("dead".to_string()));
+        message_bus.subscribe("dead".to_string(), Box::new(DeadLetterSink { letters: letters.clone() }));
+        message_bus.start();
+        message_bus.publish(Envelope::new("nobody", Ping {}));
+        wait_for(|| !letters.lock().unwrap().is_empty());
+        message_bus.stop();
+        assert_eq!(*letters.lock().unwrap(), vec!["nobody".to_string()]);
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        let ms = std::time::Duration::from_millis;
+        let simulator = |dead_letters: DeadLetters, letters: &Arc<Mutex<Vec<String>>>| {
+            let mut subscribers = pinger_and_sink(&Arc::default());
+            subscribers.insert("dead".to_string(), Box::new(DeadLetterSink { letters: letters.clone() }));
+            let mut simulator =
+                Simulator::new(subscribers, SimTime::EPOCH, vec![vec![]]).with_dead_letters(dead_letters);
+            simulator.schedule(
+                SimTime::EPOCH + ms(300),
+                SimAction::Partition(vec![vec!["pinger".to_string()], vec!["sink".to_string()]]),
+            );
+            simulator.schedule(SimTime::EPOCH + ms(600), SimAction::Heal);
+            simulator.step_to(SimTime::EPOCH + ms(1000), ms(100));
+        };
+
+        // Exactly the pings sent during the partition are lost, along with the ping sent at 200ms
+        // that was still queued when it started
+        let buffer = DeadLetterBuffer::new();
+        simulator(DeadLetters::Buffer(buffer.clone()), &Arc::new(Mutex::new(vec![])));
+        let lost = buffer.take();
+        assert_eq!(
+            lost.iter().map(|dropped| dropped.at).collect::<Vec<_>>(),
+            vec![SimTime::EPOCH + ms(300), SimTime::EPOCH + ms(200), SimTime::EPOCH + ms(400), SimTime::EPOCH + ms(500)]
+        );
+        assert!(lost.iter().all(|dropped| dropped.reason == DropReason::Partitioned
+            && dropped.envelope.destination == "sink"
+            && dropped.envelope.message.downcast_ref::<Ping>().is_some()));
+        assert!(buffer.is_empty());
+
+        // Or delivered to a dead-letter subscriber
+        let letters = Arc::new(Mutex::new(vec![]));
+        simulator(DeadLetters::Destination("dead".to_string()), &letters);
+        assert_eq!(*letters.lock().unwrap(), vec!["sink".to_string(); 4]);
+
+        // The MessageBus collects envelopes to unknown destinations
+        let buffer = DeadLetterBuffer::new();
+        let mut message_bus = MessageBus::new(ms(50), 1).with_dead_letters(DeadLetters::Buffer(buffer.clone()));
+        message_bus.start();
+        message_bus.publish(Envelope::new("nobody", Ping {}));
+        wait_for(|| !buffer.is_empty());
+        message_bus.stop();
+        let lost = buffer.take();
+        assert_eq!(lost.len(), 1);
+        assert_eq!(lost[0].reason, DropReason::UnknownDestination);
+        assert_eq!(lost[0].envelope.destination, "nobody");
+    }
+
+    #[test]
+    fn test_invariants() {
+        let ms = std::time::Duration::from_millis;
+        let held_pings = |max: usize| {
+            move |view: &SubscriberView| {
+                for (name, node) in view.all::<PingPong>() {
+                    if node.pings.len() > max {
+                        return Err(format!("{} holds {} pings", name, node.pings.len()));
+                    }
+                }
+                Ok(())
+            }
+        };
+        let mut steps = 0;
+        let mut simulator = Simulator::new(ping_pong_ring(3), SimTime::EPOCH, vec![vec![]])
+            .with_seed(7)
+            .with_invariant("every node is visible", |view| {
+                match view.all::<PingPong>().count() {
+                    3 => Ok(()),
+                    count => Err(format!("{} nodes", count)),
+                }
+            })
+            .with_invariant("steps are counted", move |view| {
+                steps += 1;
+                assert_eq!(view.time(), SimTime::EPOCH + ms(100) * steps);
+                Ok(())
+            })
+            .with_invariant("held pings", held_pings(2));
+        for _ in 0..20 {
+            simulator.step(ms(100));
+        }
+
+        // Pings are held for 300ms, so a node eventually holds 2 of them
+        let mut simulator = Simulator::new(ping_pong_ring(3), SimTime::EPOCH, vec![vec![]])
+            .with_seed(7)
+            .with_invariant("held pings", held_pings(1));
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            for _ in 0..20 {
+                simulator.step(ms(100));
+            }
+        }))
+        .unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("invariant \"held pings\" violated at 0.300000000s (seed 7): node_0 holds 2 pings"));
+        assert!(message.contains("Deliver { id: "));
+    }
+
+    #[test]
+    fn test_subscriber_state() {
+        let ms = std::time::Duration::from_millis;
+        let mut simulator = Simulator::new(ping_pong_ring(3), SimTime::EPOCH, vec![vec![]]);
+        for _ in 0..10 {
+            simulator.step(ms(100));
+        }
+        let node = simulator.subscriber::<PingPong>("node_0").unwrap();
+        assert_eq!(node.destination, "node_1");
+        assert_eq!(node.pings, [SimTime::EPOCH + ms(700), SimTime::EPOCH + ms(800)]);
+        assert!(simulator.subscriber::<Sink>("node_0").is_none());
+        assert!(simulator.subscriber::<PingPong>("node_3").is_none());
+
+        simulator.subscriber_mut::<PingPong>("node_0").unwrap().pings.clear();
+        let mut subscribers = simulator.into_subscribers();
+        assert_eq!(subscribers.len(), 3);
+        let node = subscribers.remove("node_0").unwrap().downcast::<PingPong>().ok().unwrap();
+        assert!(node.pings.is_empty());
+    }
+
+    #[test]
+    fn test_sweep() {
+        let ms = std::time::Duration::from_millis;
+        // Duplicated pings make a node hold more pings than it should, for some seeds
+        let build = |seed: u64| {
+            let none = FaultRates::default();
+            Simulator::new(ping_pong_ring(3), SimTime::EPOCH, vec![vec![]])
+                .with_seed(seed)
+                .with_faults(NetworkFaults::new(FaultRates { duplicate: 0.02, ..none }))
+                .with_invariant("held pings", |view| {
+                    match view.all::<PingPong>().find(|(_, node)| node.pings.len() > 2) {
+                        Some((name, node)) => Err(format!("{} holds {} pings", name, node.pings.len())),
+                        None => Ok(()),
+                    }
+                })
+        };
+        let report = Sweep::new(ms(1000)).with_step(ms(100)).run(0..20, build);
+        assert_eq!(report.seeds, 20);
+        assert!(!report.is_ok() && report.failures.len() < 20);
+        let failure = &report.failures[0];
+        assert!(failure.message.starts_with("invariant \"held pings\" violated at "));
+        assert_eq!(failure.reproduce, None);
+
+        // Sweeps reading DSIM_SEED print a command rerunning just the failing seed
+        let rerun = Sweep::new(ms(1000)).with_step(ms(100)).from_env().run([failure.seed], build);
+        let failure = &rerun.failures[0];
+        let reproduce = format!("DSIM_SEED={} cargo test tests::test_sweep -- --exact", failure.seed);
+        assert_eq!(failure.reproduce.as_ref(), Some(&reproduce));
+        let summary = std::panic::catch_unwind(|| rerun.assert_ok()).unwrap_err();
+        assert!(summary.downcast_ref::<String>().unwrap().contains(&reproduce));
+
+        // The same seeds fail however the sweep is spread over threads
+        let single = Sweep::new(ms(1000)).with_step(ms(100)).with_threads(1).run(0..20, build);
+        assert_eq!(single.failed_seeds(), report.failed_seeds());
+
+        // Event-driven runs without invariants
+        sweep(0..4, ms(1000), |seed| {
+            Simulator::new(ping_pong_ring(3), SimTime::EPOCH, vec![vec![]])
+                .with_seed(seed)
+                .with_tick_interval(ms(100))
+        })
+        .assert_ok();
+    }
+
+    #[test]
+    fn test_shrink() {
+        let ms = std::time::Duration::from_millis;
+        // A duplicated ping makes a node hold more pings than it should, among drops and a
+        // partition that don't matter
+        let build = |seed: u64| {
+            let none = FaultRates::default();
+            let mut simulator = Simulator::new(ping_pong_ring(3), SimTime::EPOCH, vec![vec![]])
+                .with_seed(seed)
+                .with_faults(NetworkFaults::new(FaultRates { drop: 0.2, duplicate: 0.05, ..none }))
+                .with_invariant("held pings", |view| {
+                    match view.all::<PingPong>().find(|(_, node)| node.pings.len() > 2) {
+                        Some((name, node)) => Err(format!("{} holds {} pings", name, node.pings.len())),
+                        None => Ok(()),
+                    }
+                });
+            simulator.schedule(SimTime::EPOCH + ms(200), SimAction::Partition(vec![vec!["node_0".to_string()]]));
+            simulator
+        };
+        // The faults of a run are recorded, including scheduled actions
+        let mut simulator = build(1).with_fault_recording();
+        simulator.step_to(SimTime::EPOCH + ms(800), ms(100));
+        let faults = simulator.fault_schedule().faults;
+        assert!(faults.len() > 2);
+        let duplicate = Fault::Duplicate {
+            source: "node_1".to_string(),
+            destination: "node_2".to_string(),
+            seq: 10,
+            at: SimTime::EPOCH + ms(700),
+        };
+        assert!(faults.contains(&duplicate));
+        assert!(faults.iter().any(|fault| matches!(fault, Fault::Action { action: SimAction::Partition(_), .. })));
+        // But only when asked to
+        let mut simulator = build(1);
+        simulator.step_to(SimTime::EPOCH + ms(800), ms(100));
+        assert!(simulator.fault_schedule().faults.is_empty());
+
+        // Shrunk down to the one duplicate that matters
+        let sweep = Sweep::new(ms(5000)).with_step(ms(100));
+        let shrunk = sweep.shrink(1, build).unwrap();
+        assert_eq!(shrunk.run_for, ms(800));
+        assert_eq!(shrunk.schedule.faults, vec![duplicate]);
+        assert_eq!(shrunk.schedule.to_string(), "0.700000000s duplicate node_1 -> node_2 #10");
+        assert!(shrunk.message.contains("node_2 holds 3 pings"));
+        let mut simulator = build(1).with_fault_schedule(shrunk.schedule.clone());
+        let rerun = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| simulator.step_to(SimTime::EPOCH + ms(800), ms(100))));
+        assert!(rerun.is_err());
+
+        // Sweeps shrink every failing seed
+        let report = sweep.run(1..2, build);
+        assert!(report.to_string().ends_with("shrunk to 800ms with 1 faults\n      0.700000000s duplicate node_1 -> node_2 #10"));
+        assert!(sweep.without_shrinking().run(1..2, build).failures[0].shrunk.is_none());
+    }
+
+    #[test]
+    fn test_scenario() {
+        let ms = std::time::Duration::from_millis;
+        let text = "
+            # The sink is cut off for a while
+            at 300ms partition {pinger} | {sink}
+            at 0.6s heal; at 700ms crash sink
+            at 1s restart sink
+        ";
+        let scenario = Scenario::parse(text).unwrap();
+        let expected = Scenario::new()
+            .at(ms(300), SimAction::Partition(vec![vec!["pinger".to_string()], vec!["sink".to_string()]]))
+            .at(ms(600), SimAction::Heal)
+            .at(ms(700), SimAction::Crash("sink".to_string()))
+            .at(ms(1000), SimAction::Restart("sink".to_string()));
+        assert_eq!(scenario, expected);
+        assert_eq!(
+            scenario.to_string(),
+            "at 300ms partition pinger | sink\nat 600ms heal\nat 700ms crash sink\nat 1s restart sink\n"
+        );
+        assert_eq!(Scenario::read_from(scenario.to_string().as_bytes()).unwrap(), scenario);
+        let error = Scenario::parse("at 1s heal\nat 2s explode sink").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: unknown action \"at 2s explode sink\"");
+        let error = Scenario::parse("at 18446744073.9s heal").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: duration \"18446744073.9s\" is too large");
+
+        // Scripted actions combine with random faults
+        let received = Arc::new(Mutex::new(vec![]));
+        let counter = DropCounter::default();
+        let none = FaultRates::default();
+        let mut simulator = Simulator::with_hook(
+            pinger_and_sink(&received),
+            SimTime::EPOCH,
+            vec![vec![]],
+            counter.clone(),
+        )
+        .with_faults(NetworkFaults::new(FaultRates { duplicate: 1.0, ..none }))
+        .with_scenario(scenario);
+        simulator.step_to(SimTime::EPOCH + ms(1500), ms(100));
+        // Pings sent at 300, 400 and 500ms are partitioned along with both copies of the ping still
+        // queued from 200ms, and both copies of the pings sent at 600, 700 and 800ms are lost while
+        // the sink is down
+        assert_eq!(*counter.drops.lock().unwrap(), 3 + 2 + 2 * 3);
+        assert_eq!(received.lock().unwrap().len(), 2 * 7);
+    }
+
+    #[test]
+    fn test_trace_inspection() {
+        let ms = std::time::Duration::from_millis;
+        let record = |partition_at: u64| {
+            let recorder = Recorder::new();
+            let mut simulator = Simulator::with_hook(
+                pinger_and_sink(&Arc::default()),
+                SimTime::EPOCH,
+                vec![vec![]],
+                recorder.clone(),
+            )
+            .with_scenario(Scenario::parse(&format!("at {}ms partition pinger | sink; at 600ms heal", partition_at)).unwrap());
+            simulator.step_to(SimTime::EPOCH + ms(1000), ms(100));
+            recorder.take()
+        };
+        let trace = record(300);
+
+        let summary = trace.summary();
+        let link = summary.links[&("pinger".to_string(), "sink".to_string())];
+        assert_eq!((link.published, link.delivered, link.dropped), (10, 5, 4));
+        assert_eq!(summary.drops, maplit::btreemap! { "Partitioned".to_string() => 4 });
+        assert_eq!(summary.ticks, 10);
+        assert!(summary.to_string().contains("pinger -> sink: 10 published, 5 delivered, 4 dropped"));
+
+        // Dead letters are delivered on the link to the dead-letter subscriber, not the original one
+        let recorder = Recorder::new();
+        let mut subscribers = pinger_and_sink(&Arc::default());
+        subscribers.insert("dead".to_string(), Box::new(DeadLetterSink { letters: Arc::default() }));
+        let mut simulator = Simulator::with_hook(subscribers, SimTime::EPOCH, vec![vec![]], recorder.clone())
+            .with_dead_letters(DeadLetters::Destination("dead".to_string()))
+            .with_scenario(Scenario::parse("at 300ms partition pinger | sink; at 600ms heal").unwrap());
+        simulator.step_to(SimTime::EPOCH + ms(1000), ms(100));
+        let summary = recorder.take().summary();
+        let link = summary.links[&("pinger".to_string(), "sink".to_string())];
+        assert_eq!((link.published, link.delivered, link.dropped), (10, 5, 4));
+        let dead = summary.links[&("pinger".to_string(), "dead".to_string())];
+        assert_eq!((dead.published, dead.delivered, dead.dropped), (0, 4, 0));
+
+        let filter = TraceFilter::new()
+            .with_destination("sink")
+            .with_message_type("Ping")
+            .with_from("0.3s".parse().unwrap())
+            .with_to("400ms".parse().unwrap());
+        let lines: Vec<String> = filter.apply(&trace).iter().map(|event| event.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "0.300000000s publish #4 pinger -> sink dsim::tests::Ping",
+                "0.300000000s drop #4 to sink (Partitioned)",
+                "0.400000000s publish #5 pinger -> sink dsim::tests::Ping",
+                "0.400000000s drop #5 to sink (Partitioned)",
+            ]
+        );
+
+        assert_eq!(trace.first_divergence(&record(300)), None);
+        let diverged = record(400);
+        let i = trace.first_divergence(&diverged).unwrap();
+        assert_eq!(trace.events[i].to_string(), "0.300000000s drop #4 to sink (Partitioned)");
+        assert_eq!(diverged.events[i].to_string(), "0.200000000s deliver #3 to sink");
+
+        // The CLI reads trace files
+        let dir = std::env::temp_dir();
+        let a = dir.join(format!("dsim-cli-{}-a.trace", std::process::id()));
+        let b = dir.join(format!("dsim-cli-{}-b.trace", std::process::id()));
+        trace.write_to(std::fs::File::create(&a).unwrap(), TraceFormat::JsonLines).unwrap();
+        diverged.write_to(std::fs::File::create(&b).unwrap(), TraceFormat::Binary).unwrap();
+        let run = |args: &[&str]| super::run(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>());
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+        assert_eq!(run(&["summary", a]), Ok(std::process::ExitCode::SUCCESS));
+        assert_eq!(run(&["print", a, "--destination", "sink", "--from", "1s"]), Ok(std::process::ExitCode::SUCCESS));
+        assert_eq!(run(&["step", b, "--type", "Ping"]), Ok(std::process::ExitCode::SUCCESS));
+        assert_eq!(run(&["diff", a, a]), Ok(std::process::ExitCode::SUCCESS));
+        assert_eq!(run(&["diff", a, b]), Ok(std::process::ExitCode::from(1)));
+        assert_eq!(run(&["diff", a]), Err("wrong number of arguments for diff".to_string()));
+        assert_eq!(run(&["print", a, "--to"]), Err("missing value for --to".to_string()));
+        assert_eq!(run(&["print", a, "--from", "18446744073.9s"]), Err("duration \"18446744073.9s\" is too large".to_string()));
+        assert!(run(&["summary", "/nonexistent/dsim.trace"]).is_err());
+        std::fs::remove_file(a).unwrap();
+        std::fs::remove_file(b).unwrap();
+    }
+
     #[test]
     fn test_simulator_step_by() {
         let ping_pong_1 = PingPong::new(
@@ -120,7 +1618,7 @@ mod tests {
                 "ping_pong_1".to_string() => Box::new(ping_pong_1) as Box<dyn Subscriber>,
                 "ping_pong_2".to_string() => Box::new(ping_pong_2) as Box<dyn Subscriber>,
             },
-            UNIX_EPOCH,
+            SimTime::EPOCH,
             vec![vec![], vec![]], // Create 2 priority queues
         );
 
@@ -148,10 +1646,10 @@ mod tests {
                 "ping_pong_1".to_string() => Box::new(ping_pong_1) as Box<dyn Subscriber>,
                 "ping_pong_2".to_string() => Box::new(ping_pong_2) as Box<dyn Subscriber>,
             },
-            UNIX_EPOCH,
+            SimTime::EPOCH,
             vec![vec![], vec![]], // Create 2 priority queues
         );
 
-        simulator.step_to(UNIX_EPOCH + std::time::Duration::from_secs(5), std::time::Duration::from_millis(100));
+        simulator.step_to(SimTime::EPOCH + std::time::Duration::from_secs(5), std::time::Duration::from_millis(100));
     }
 }
@@ -0,0 +1,22 @@
+/// A change to a running [crate::message_bus::Simulator], applied immediately with
+/// [crate::message_bus::Simulator::apply] or at a virtual time with
+/// [crate::message_bus::Simulator::schedule].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimAction {
+    /// Splits destinations into groups, envelopes can only be delivered within a group.
+    ///
+    /// Destinations not listed in any group can still reach, and be reached by, everyone.
+    /// Replaces any previous partition.
+    Partition(Vec<Vec<String>>),
+    /// Removes the partition and restores every cut link.
+    Heal,
+    /// Stops delivering envelopes from `source` to `destination`. The reverse direction is
+    /// not affected.
+    CutLink { source: String, destination: String },
+    /// Undoes a [SimAction::CutLink].
+    RestoreLink { source: String, destination: String },
+    /// Stops ticking and delivering to a subscriber, dropping any envelopes in flight to it.
+    Crash(String),
+    /// Resumes a crashed subscriber, see [crate::message_bus::Simulator::restart].
+    Restart(String),
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use crate::message_bus::SimTime;
+
+/// A subscriber's local clock in the [crate::message_bus::Simulator], which can disagree with
+/// the simulator's global time.
+///
+/// The clock starts `offset` ahead of (or behind) the global time when the simulation starts,
+/// and then runs `drift` faster (or slower, when negative) than it: a drift of `0.001` gains
+/// 1ms every second. Every `at` the subscriber is passed, and every timer it sets, is in its
+/// local time.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Clock {
+    /// Offset from global time at the start of the simulation
+    offset_nanos: i128,
+    drift: f64,
+}
+
+impl Clock {
+    /// A clock that starts `offset` ahead of global time.
+    pub fn ahead(offset: Duration) -> Self {
+        Self {
+            offset_nanos: offset.as_nanos() as i128,
+            drift: 0.0,
+        }
+    }
+
+    /// A clock that starts `offset` behind global time.
+    ///
+    /// Local times can't go before [SimTime::EPOCH], so the simulation must start at least
+    /// `offset` after it.
+    pub fn behind(offset: Duration) -> Self {
+        Self {
+            offset_nanos: -(offset.as_nanos() as i128),
+            drift: 0.0,
+        }
+    }
+
+    /// Sets the rate the clock runs faster (positive) or slower (negative) than global time.
+    /// Must be greater than `-1.0`, a clock can't run backwards.
+    pub fn with_drift(mut self, drift: f64) -> Self {
+        assert!(drift > -1.0, "clock drift must be greater than -1.0");
+        self.drift = drift;
+        self
+    }
+
+    /// Draws a clock with an offset uniformly distributed in `[-max_offset, max_offset]` and a
+    /// drift uniformly distributed in `[-max_drift, max_drift)`.
+    pub(crate) fn random(clocks: RandomClocks, rng: &mut crate::message_bus::SimRng) -> Self {
+        let max_offset = clocks.max_offset.as_nanos().min(i64::MAX as u128) as u64;
+        let offset_nanos = rng.gen_range(0, max_offset.saturating_mul(2).saturating_add(1)) as i128
+            - max_offset as i128;
+        let drift = (rng.next_f64() * 2.0 - 1.0) * clocks.max_drift;
+        Self {
+            offset_nanos,
+            drift: drift.max(-0.999),
+        }
+    }
+
+    /// Whether the clock never reads before [SimTime::EPOCH] in a simulation started at
+    /// `origin`. Drift can't make a clock run backwards, so it reads the earliest at `origin`.
+    pub(crate) fn fits(&self, origin: SimTime) -> bool {
+        origin.as_nanos() as i128 + self.offset_nanos >= 0
+    }
+
+    /// The local time when the global time is `global`, for a simulation started at `origin`.
+    pub(crate) fn local(&self, origin: SimTime, global: SimTime) -> SimTime {
+        let elapsed = global.duration_since(origin).as_nanos() as i128;
+        let local = elapsed + (elapsed as f64 * self.drift) as i128 + self.offset_nanos;
+        offset(origin, local)
+    }
+
+    /// The global time when the local time is `local`, for a simulation started at `origin`.
+    /// Local times from before the simulation started map to `origin`.
+    pub(crate) fn global(&self, origin: SimTime, local: SimTime) -> SimTime {
+        let skewed = local.as_nanos() as i128 - origin.as_nanos() as i128 - self.offset_nanos;
+        let elapsed = skewed - (skewed as f64 * self.drift / (1.0 + self.drift)) as i128;
+        offset(origin, elapsed.max(0))
+    }
+}
+
+/// `origin` shifted by a signed number of nanoseconds, saturating at [SimTime::EPOCH] and
+/// [SimTime::MAX]. Clocks that [Clock::fits] their simulation never reach [SimTime::EPOCH].
+fn offset(origin: SimTime, nanos: i128) -> SimTime {
+    let shifted = (origin.as_nanos() as i128 + nanos).clamp(0, u64::MAX as i128);
+    SimTime::from_nanos(shifted as u64)
+}
+
+/// Gives every subscriber of the [crate::message_bus::Simulator] a random [Clock], driven by
+/// the simulation seed and the subscriber's name.
+///
+/// Each clock starts with an offset uniformly distributed between `-max_offset` and
+/// `max_offset`, and drifts at a rate uniformly distributed between `-max_drift` and `max_drift`.
+/// The simulation must start at least `max_offset` after [SimTime::EPOCH], see [Clock::behind].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RandomClocks {
+    pub max_offset: Duration,
+    pub max_drift: f64,
+}
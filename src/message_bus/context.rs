@@ -0,0 +1,66 @@
+use crate::message_bus::{Envelope, SimRng, SimTime, Subscriber};
+
+/// Passed to every [crate::message_bus::Subscriber] call, giving access to facilities that
+/// the [crate::message_bus::Simulator] or [crate::message_bus::MessageBus] provides.
+pub struct Context<'a> {
+    name: &'a str,
+    rng: &'a mut SimRng,
+    spawned: Vec<(String, Box<dyn Subscriber>)>,
+    timers: Vec<(SimTime, u64)>,
+}
+
+/// Everything a subscriber produced during one call.
+pub(crate) struct Outcome {
+    pub(crate) envelopes: Vec<Envelope>,
+    pub(crate) spawned: Vec<(String, Box<dyn Subscriber>)>,
+    pub(crate) timers: Vec<(SimTime, u64)>,
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn new(name: &'a str, rng: &'a mut SimRng) -> Self {
+        Self {
+            name,
+            rng,
+            spawned: Vec::new(),
+            timers: Vec::new(),
+        }
+    }
+
+    pub(crate) fn finish(self, envelopes: Vec<Envelope>) -> Outcome {
+        Outcome {
+            envelopes,
+            spawned: self.spawned,
+            timers: self.timers,
+        }
+    }
+
+    /// The destination name this subscriber is registered under.
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    /// The subscriber's own random number generator, derived from the simulation seed and the
+    /// subscriber's name. Use this for any randomized decision (election timeouts, jitter, ...)
+    /// so that runs reproduce exactly from one seed.
+    pub fn rng(&mut self) -> &mut SimRng {
+        self.rng
+    }
+
+    /// Asks to be woken up at `at`: [crate::message_bus::Subscriber::timer] is called with
+    /// `token` once that time is reached. A timer set for a time that already passed fires as soon
+    /// as possible: on the next step of the [crate::message_bus::Simulator], or once this call
+    /// returns on the [crate::message_bus::MessageBus].
+    ///
+    /// Use the token to tell timers apart, e.g. to ignore an election timeout that was reset
+    /// after it was set. Pending timers are discarded if the subscriber crashes.
+    pub fn set_timer(&mut self, at: SimTime, token: u64) {
+        self.timers.push((at, token));
+    }
+
+    /// Registers a new subscriber under `destination` once this call returns, replacing any
+    /// existing subscriber with that name (and cancelling its timers). It is ticked from the next tick on, and can be sent
+    /// envelopes right away (including the ones returned from this call).
+    pub fn spawn(&mut self, destination: impl Into<String>, subscriber: Box<dyn Subscriber>) {
+        self.spawned.push((destination.into(), subscriber));
+    }
+}
@@ -0,0 +1,14 @@
+use std::time::Duration;
+
+/// Randomly crashes and later restarts subscribers of the [crate::message_bus::Simulator],
+/// driven by the simulation seed.
+///
+/// Each running subscriber crashes after an exponentially distributed uptime with mean
+/// `mean_time_between_crashes`, stays down for a duration uniformly distributed between
+/// `min_downtime` and `max_downtime`, and is then restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RandomCrashes {
+    pub mean_time_between_crashes: Duration,
+    pub min_downtime: Duration,
+    pub max_downtime: Duration,
+}
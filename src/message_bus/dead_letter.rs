@@ -0,0 +1,168 @@
+use std::sync::{Arc, Mutex};
+
+use crate::message_bus::{DropReason, Envelope, Message, PublishHook, SimTime, Subscribers};
+
+/// What the [crate::message_bus::Simulator] and [crate::message_bus::MessageBus] do with an
+/// envelope whose destination has no subscriber.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum UnknownDestination {
+    /// Panic, describing the envelope. Useful to catch typos in destination names, and the
+    /// default of the [crate::message_bus::Simulator].
+    Panic,
+    /// Drop the envelope like any other undeliverable envelope: report it to
+    /// [PublishHook::on_drop] with [DropReason::UnknownDestination], and hand it to the
+    /// [DeadLetters] policy (e.g. [DeadLetters::Destination] to deliver it to a dead-letter
+    /// subscriber). The default of the [crate::message_bus::MessageBus].
+    #[default]
+    Drop,
+}
+
+impl UnknownDestination {
+    /// Returns the envelope to deliver in place of `envelope`, if any.
+    pub(crate) fn route<H: PublishHook>(
+        &self,
+        subscribers: &Subscribers,
+        dead_letters: &DeadLetters,
+        hook: &H,
+        envelope: Envelope,
+        at: SimTime,
+    ) -> Option<Envelope> {
+        if subscribers.get(&envelope.destination).is_some() {
+            return Some(envelope);
+        }
+        // The envelope was already reported when it became a dead letter, see
+        // [DeadLetters::Destination]
+        if envelope.message.downcast_ref::<DeadLetter>().is_some() {
+            return None;
+        }
+        let envelope = match self {
+            UnknownDestination::Panic => panic!("no subscriber for envelope {:?} at {}", envelope, at),
+            UnknownDestination::Drop => dead_letters.drop(hook, envelope, DropReason::UnknownDestination, at)?,
+        };
+        // The dead-letter subscriber may not exist either
+        subscribers.get(&envelope.destination)?;
+        Some(envelope)
+    }
+}
+
+/// Delivered to a dead-letter subscriber in place of an envelope that could not be delivered,
+/// see [DeadLetters::Destination]. The envelope keeps its id
+/// and source.
+#[derive(Debug)]
+pub struct DeadLetter {
+    /// The original destination
+    pub destination: String,
+    pub reason: DropReason,
+    pub message: Box<dyn Message>,
+}
+
+impl DeadLetter {
+    /// Readdresses `envelope` to `dead_letter`, wrapping its message.
+    pub(crate) fn wrap(envelope: Envelope, dead_letter: &str, reason: DropReason) -> Envelope {
+        let message = DeadLetter {
+            destination: envelope.destination,
+            reason,
+            message: envelope.message,
+        };
+        Envelope {
+            message: Box::new(message),
+            destination: dead_letter.to_string(),
+            ..envelope
+        }
+    }
+}
+
+impl Message for DeadLetter {
+    fn try_clone(&self) -> Option<Box<dyn Message>> {
+        Some(Box::new(DeadLetter {
+            destination: self.destination.clone(),
+            reason: self.reason,
+            message: self.message.try_clone()?,
+        }))
+    }
+
+    fn describe(&self) -> Option<String> {
+        Some(format!("{:?}", self))
+    }
+}
+
+/// What happens to envelopes that will never be delivered (dropped by faults, partitions,
+/// crashes, unsubscribing, or sent to an unknown destination), after they are reported to
+/// [PublishHook::on_drop].
+#[derive(Debug, Clone, Default)]
+pub enum DeadLetters {
+    /// Discard them.
+    #[default]
+    Discard,
+    /// Collect them into a buffer, to assert on what was lost.
+    Buffer(DeadLetterBuffer),
+    /// Deliver them to the subscriber with this name as a [DeadLetter]. Dead letters that can't
+    /// be delivered themselves are discarded.
+    Destination(String),
+}
+
+impl DeadLetters {
+    /// Reports a dropped envelope and applies the policy. Returns the dead letter to deliver, if
+    /// any.
+    pub(crate) fn drop<H: PublishHook>(
+        &self,
+        hook: &H,
+        envelope: Envelope,
+        reason: DropReason,
+        at: SimTime,
+    ) -> Option<Envelope> {
+        hook.on_drop(&envelope, reason, at);
+        match self {
+            DeadLetters::Discard => None,
+            DeadLetters::Buffer(buffer) => {
+                buffer.letters.lock().unwrap().push(DroppedEnvelope { envelope, reason, at });
+                None
+            }
+            DeadLetters::Destination(dead_letter) => {
+                // Never readdress a dead letter, that could loop forever
+                if envelope.message.downcast_ref::<DeadLetter>().is_some() {
+                    return None;
+                }
+                Some(DeadLetter::wrap(envelope, dead_letter, reason))
+            }
+        }
+    }
+}
+
+/// An envelope that was never delivered, collected by a [DeadLetterBuffer].
+#[derive(Debug)]
+pub struct DroppedEnvelope {
+    pub envelope: Envelope,
+    pub reason: DropReason,
+    /// When it was dropped
+    pub at: SimTime,
+}
+
+/// Collects the envelopes that were never delivered, see [DeadLetters::Buffer].
+///
+/// Clones share the same buffer, so keep a clone around to inspect it.
+#[derive(Debug, Clone, Default)]
+pub struct DeadLetterBuffer {
+    letters: Arc<Mutex<Vec<DroppedEnvelope>>>,
+}
+
+impl DeadLetterBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many envelopes were collected.
+    pub fn len(&self) -> usize {
+        self.letters.lock().unwrap().len()
+    }
+
+    /// Whether nothing was lost (so far).
+    pub fn is_empty(&self) -> bool {
+        self.letters.lock().unwrap().is_empty()
+    }
+
+    /// Takes every envelope collected so far, in the order they were dropped.
+    pub fn take(&self) -> Vec<DroppedEnvelope> {
+        std::mem::take(&mut *self.letters.lock().unwrap())
+    }
+}
@@ -1,12 +1,125 @@
 use std::any::Any;
 
+use crate::message_bus::{SerializedMessage, SimTime};
+
+#[derive(Debug)]
 pub struct Envelope {
   pub message: Box<dyn Message>,
   pub priority: usize,
   pub destination: String,
+  /// The destination name of the subscriber that sent this envelope. Stamped by the
+  /// [crate::message_bus::Simulator] and [crate::message_bus::MessageBus] when they route an
+  /// envelope emitted by a subscriber, empty for envelopes published from outside.
+  pub source: String,
+  /// How long to hold the envelope before delivering it, on top of any simulated network
+  /// latency. Useful to send a message to the future, e.g. "retry in 500ms" to yourself.
+  pub delay: std::time::Duration,
+  /// Identifies the envelope within a run, stamped by the [crate::message_bus::Simulator] and
+  /// [crate::message_bus::MessageBus] when it is published (0 until then). Ids are handed out in
+  /// publish order, starting at 1.
+  pub id: u64,
+}
+
+impl Envelope {
+    /// Creates an envelope with the lowest priority (0).
+    pub fn new(destination: impl Into<String>, message: impl Message) -> Self {
+        Self {
+            message: Box::new(message),
+            priority: 0,
+            destination: destination.into(),
+            source: String::new(),
+            delay: std::time::Duration::ZERO,
+            id: 0,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: usize) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Delivers the envelope `delay` after it is published, see [Envelope::delay].
+    pub fn with_delay(mut self, delay: std::time::Duration) -> Self {
+        self.delay = delay;
+        self
+    }
 }
 
-pub trait Message: Any + Send + 'static {}
+pub trait Message: Any + Send + 'static {
+    /// Returns a copy of this message, if the message supports it.
+    ///
+    /// Needed for the [crate::message_bus::Simulator] to duplicate the message as a network fault,
+    /// messages that don't override this are never duplicated.
+    fn try_clone(&self) -> Option<Box<dyn Message>> {
+        None
+    }
+
+    /// Returns the contents of this message for trace files, if the message supports it.
+    ///
+    /// Messages that don't override this are written to trace files without their contents.
+    /// With the `serde` feature, [SerializedMessage::json] serializes any [serde] type.
+    fn to_serialized(&self) -> Option<SerializedMessage> {
+        None
+    }
+
+    /// The name of the message type, shown in traces and panic messages.
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// A human readable description of the message contents, shown in traces and panic messages
+    /// instead of just the [Message::type_name].
+    ///
+    /// Use [crate::debug_message] to describe a message with its [std::fmt::Debug] representation.
+    fn describe(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Implements [Message] for a type, describing it (see [Message::describe]) with its
+/// [std::fmt::Debug] representation. Pass `clone` as well to implement [Message::try_clone] with
+/// [Clone].
+///
+/// ```
+/// #[derive(Debug, Clone)]
+/// struct Ping {
+///     seq: u64,
+/// }
+///
+/// dsim::debug_message!(Ping, clone);
+/// ```
+#[macro_export]
+macro_rules! debug_message {
+    ($type:ty) => {
+        impl $crate::message_bus::Message for $type {
+            fn describe(&self) -> Option<String> {
+                Some(format!("{:?}", self))
+            }
+        }
+    };
+    ($type:ty, clone) => {
+        impl $crate::message_bus::Message for $type {
+            fn describe(&self) -> Option<String> {
+                Some(format!("{:?}", self))
+            }
+
+            fn try_clone(&self) -> Option<Box<dyn $crate::message_bus::Message>> {
+                Some(Box::new(self.clone()))
+            }
+        }
+    };
+}
+
+/// Shows the [Message::describe] description, or the [Message::type_name] for messages that
+/// don't describe themselves.
+impl std::fmt::Debug for dyn Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.describe() {
+            Some(description) => f.write_str(&description),
+            None => f.write_str(self.type_name()),
+        }
+    }
+}
 
 impl dyn Message {
     pub fn as_any(&self) -> &(dyn Any + Send) {
@@ -21,6 +134,7 @@ impl dyn Message {
         self.as_any().downcast_ref::<T>()
     }
 
+    #[allow(clippy::needless_return)]
     pub fn downcast<T: Message>(self: Box<Self>) -> Result<Box<T>, Box<dyn Message>> {
         if self.as_any().is::<T>() {
             let boxed_any = self.into_any();
@@ -33,10 +147,161 @@ impl dyn Message {
     }
 }
 
-/// A hook that is called whenever an envelope is published.
-/// Useful for recording messages for replay, logging, or debugging.
+/// Why an envelope was not delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DropReason {
+    /// Dropped by the simulator's [crate::message_bus::NetworkFaults]
+    Fault,
+    /// The destination is unreachable from the source, see [crate::message_bus::SimAction]
+    Partitioned,
+    /// The destination crashed before the envelope was delivered
+    Crashed,
+    /// The destination was unsubscribed before the envelope was delivered
+    Unsubscribed,
+    /// No subscriber is registered under the destination
+    UnknownDestination,
+}
+
+/// A hook that is called whenever an envelope is published, and that can observe the rest of
+/// the lifecycle of envelopes and subscribers (deliveries, drops, ticks, timers, crashes and
+/// steps) with the other methods.
+/// Useful for recording messages for replay (see [crate::message_bus::Recorder]), logging, metrics, or debugging.
+///
+/// Combine hooks with a tuple: `(A, B)` calls `A` then `B`.
 pub trait PublishHook: Send + 'static {
-    fn on_publish(&self, envelope: &Envelope, at: std::time::SystemTime);
+    fn on_publish(&self, envelope: &Envelope, at: SimTime);
+
+    /// Called when a published envelope will never be delivered.
+    fn on_drop(&self, _envelope: &Envelope, _reason: DropReason, _at: SimTime) {}
+
+    /// Called right before every running subscriber is ticked at `at`.
+    fn on_tick(&self, _at: SimTime) {}
+
+    /// Called right before a subscriber's timer fires, `at` is the time it was set for.
+    fn on_timer(&self, _destination: &str, _token: u64, _at: SimTime) {}
+
+    /// Called right before an envelope is handed to its destination at `at`.
+    fn on_deliver(&self, _envelope: &Envelope, _at: SimTime) {}
+
+    /// Called when the [crate::message_bus::Simulator] crashes a subscriber.
+    fn on_crash(&self, _destination: &str, _at: SimTime) {}
+
+    /// Called when the [crate::message_bus::Simulator] restarts a crashed subscriber.
+    fn on_restart(&self, _destination: &str, _at: SimTime) {}
+
+    /// Called at the start of every [crate::message_bus::Simulator] step, and of every
+    /// [crate::message_bus::MessageBus] loop iteration that processes something (ticks, timers
+    /// or an envelope).
+    fn on_step_start(&self, _at: SimTime) {}
+
+    /// Called at the end of every step started with [PublishHook::on_step_start], `at` is the
+    /// time after the step.
+    fn on_step_end(&self, _at: SimTime) {}
+}
+
+impl<A: PublishHook, B: PublishHook> PublishHook for (A, B) {
+    fn on_publish(&self, envelope: &Envelope, at: SimTime) {
+        self.0.on_publish(envelope, at);
+        self.1.on_publish(envelope, at);
+    }
+
+    fn on_drop(&self, envelope: &Envelope, reason: DropReason, at: SimTime) {
+        self.0.on_drop(envelope, reason, at);
+        self.1.on_drop(envelope, reason, at);
+    }
+
+    fn on_tick(&self, at: SimTime) {
+        self.0.on_tick(at);
+        self.1.on_tick(at);
+    }
+
+    fn on_timer(&self, destination: &str, token: u64, at: SimTime) {
+        self.0.on_timer(destination, token, at);
+        self.1.on_timer(destination, token, at);
+    }
+
+    fn on_deliver(&self, envelope: &Envelope, at: SimTime) {
+        self.0.on_deliver(envelope, at);
+        self.1.on_deliver(envelope, at);
+    }
+
+    fn on_crash(&self, destination: &str, at: SimTime) {
+        self.0.on_crash(destination, at);
+        self.1.on_crash(destination, at);
+    }
+
+    fn on_restart(&self, destination: &str, at: SimTime) {
+        self.0.on_restart(destination, at);
+        self.1.on_restart(destination, at);
+    }
+
+    fn on_step_start(&self, at: SimTime) {
+        self.0.on_step_start(at);
+        self.1.on_step_start(at);
+    }
+
+    fn on_step_end(&self, at: SimTime) {
+        self.0.on_step_end(at);
+        self.1.on_step_end(at);
+    }
+}
+
+/// Calls the hook, if there is one.
+impl<H: PublishHook> PublishHook for Option<H> {
+    fn on_publish(&self, envelope: &Envelope, at: SimTime) {
+        if let Some(hook) = self {
+            hook.on_publish(envelope, at);
+        }
+    }
+
+    fn on_drop(&self, envelope: &Envelope, reason: DropReason, at: SimTime) {
+        if let Some(hook) = self {
+            hook.on_drop(envelope, reason, at);
+        }
+    }
+
+    fn on_tick(&self, at: SimTime) {
+        if let Some(hook) = self {
+            hook.on_tick(at);
+        }
+    }
+
+    fn on_timer(&self, destination: &str, token: u64, at: SimTime) {
+        if let Some(hook) = self {
+            hook.on_timer(destination, token, at);
+        }
+    }
+
+    fn on_deliver(&self, envelope: &Envelope, at: SimTime) {
+        if let Some(hook) = self {
+            hook.on_deliver(envelope, at);
+        }
+    }
+
+    fn on_crash(&self, destination: &str, at: SimTime) {
+        if let Some(hook) = self {
+            hook.on_crash(destination, at);
+        }
+    }
+
+    fn on_restart(&self, destination: &str, at: SimTime) {
+        if let Some(hook) = self {
+            hook.on_restart(destination, at);
+        }
+    }
+
+    fn on_step_start(&self, at: SimTime) {
+        if let Some(hook) = self {
+            hook.on_step_start(at);
+        }
+    }
+
+    fn on_step_end(&self, at: SimTime) {
+        if let Some(hook) = self {
+            hook.on_step_end(at);
+        }
+    }
 }
 
 /// A no-op hook that does nothing when envelopes are published.
@@ -45,5 +310,5 @@ pub struct NoOpHook;
 
 impl PublishHook for NoOpHook {
     #[inline(always)]
-    fn on_publish(&self, _envelope: &Envelope, _at: std::time::SystemTime) {}
+    fn on_publish(&self, _envelope: &Envelope, _at: SimTime) {}
 }
@@ -0,0 +1,168 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::message_bus::{Envelope, Message, SimAction, SimTime};
+
+/// Probabilities (between 0 and 1) of each fault being applied to a single envelope.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FaultRates {
+    /// The envelope is never delivered
+    pub drop: f64,
+    /// The envelope is delivered twice, only for messages that implement [Message::try_clone]
+    pub duplicate: f64,
+    /// The envelope is held back for a random extra delay, so envelopes sent after it
+    /// can overtake it
+    pub reorder: f64,
+}
+
+/// A fault injection policy for the [crate::message_bus::Simulator] network.
+///
+/// Every decision is drawn from the simulation seed, so a run with faults reproduces exactly.
+///
+/// Rates are looked up by message type first, then by directed link, and finally fall back to
+/// the default rates. Rules do not combine: an envelope whose message type has a rule gets
+/// exactly those rates, even on a link with its own rule.
+#[derive(Debug, Clone)]
+pub struct NetworkFaults {
+    default: FaultRates,
+    links: HashMap<(String, String), FaultRates>,
+    messages: HashMap<TypeId, FaultRates>,
+    reorder_delay: Duration,
+}
+
+impl NetworkFaults {
+    /// Creates a policy applying `default` to every envelope without a more specific rule.
+    pub fn new(default: FaultRates) -> Self {
+        Self {
+            default,
+            links: HashMap::new(),
+            messages: HashMap::new(),
+            reorder_delay: Duration::from_millis(100),
+        }
+    }
+
+    /// Sets the rates for envelopes sent from `source` to `destination`.
+    pub fn with_link(mut self, source: &str, destination: &str, rates: FaultRates) -> Self {
+        self.links
+            .insert((source.to_string(), destination.to_string()), rates);
+        self
+    }
+
+    /// Sets the rates for envelopes carrying a message of type `T`, regardless of link. These
+    /// replace (rather than add to) any rates set with [NetworkFaults::with_link].
+    pub fn with_message<T: Message>(mut self, rates: FaultRates) -> Self {
+        self.messages.insert(TypeId::of::<T>(), rates);
+        self
+    }
+
+    /// Sets the maximum extra delay of a reordered envelope, defaults to 100ms.
+    ///
+    /// This should be at least the step size used to drive the simulator, otherwise reordered
+    /// envelopes may still be delivered in the same step.
+    pub fn with_reorder_delay(mut self, max: Duration) -> Self {
+        self.reorder_delay = max;
+        self
+    }
+
+    pub(crate) fn reorder_delay(&self) -> Duration {
+        self.reorder_delay
+    }
+
+    pub(crate) fn rates_for(&self, source: &str, envelope: &Envelope) -> FaultRates {
+        if let Some(rates) = self.messages.get(&envelope.message.as_any().type_id()) {
+            return *rates;
+        }
+        self.links
+            .get(&(source.to_string(), envelope.destination.clone()))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// A fault injected into a [crate::message_bus::Simulator] run, see [FaultSchedule].
+///
+/// Envelope faults identify their envelope by its link and `seq`, its position among the
+/// envelopes published from `source` to `destination` (starting at 1). Unlike [Envelope::id]s,
+/// these stay put when faults on other links are added or removed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// The envelope was dropped.
+    Drop {
+        source: String,
+        destination: String,
+        seq: u64,
+        at: SimTime,
+    },
+    /// The envelope was delivered twice.
+    Duplicate {
+        source: String,
+        destination: String,
+        seq: u64,
+        at: SimTime,
+    },
+    /// The envelope was held back `by` an extra delay.
+    Delay {
+        source: String,
+        destination: String,
+        seq: u64,
+        by: Duration,
+        at: SimTime,
+    },
+    /// A scheduled action (partition, crash, ...) was applied, including random crashes and
+    /// restarts.
+    Action { action: SimAction, at: SimTime },
+}
+
+impl Fault {
+    /// The time the fault was injected at.
+    pub fn at(&self) -> SimTime {
+        match self {
+            Fault::Drop { at, .. }
+            | Fault::Duplicate { at, .. }
+            | Fault::Delay { at, .. }
+            | Fault::Action { at, .. } => *at,
+        }
+    }
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::Drop { source, destination, seq, at } => {
+                write!(f, "{} drop {} -> {} #{}", at, source, destination, seq)
+            }
+            Fault::Duplicate { source, destination, seq, at } => {
+                write!(f, "{} duplicate {} -> {} #{}", at, source, destination, seq)
+            }
+            Fault::Delay { source, destination, seq, by, at } => {
+                write!(f, "{} delay {} -> {} #{} by {:?}", at, source, destination, seq, by)
+            }
+            Fault::Action { action, at } => write!(f, "{} {:?}", at, action),
+        }
+    }
+}
+
+/// The faults injected into a [crate::message_bus::Simulator] run, in the order they happened.
+///
+/// Record it with [crate::message_bus::Simulator::with_fault_recording] and read it with
+/// [crate::message_bus::Simulator::fault_schedule], then inject exactly these faults into another
+/// run with [crate::message_bus::Simulator::with_fault_schedule]. Envelope faults refer to the
+/// n-th envelope on a link (see [Fault]), so they line up with runs that publish the same
+/// envelopes on that link.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultSchedule {
+    pub faults: Vec<Fault>,
+}
+
+impl std::fmt::Display for FaultSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, fault) in self.faults.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", fault)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::message_bus::{SimTime, Trace, TraceEvent};
+
+/// Selects the events of a [Trace] to look at, see [TraceFilter::apply].
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    destination: Option<String>,
+    message_type: Option<String>,
+    from: Option<SimTime>,
+    to: Option<SimTime>,
+}
+
+impl TraceFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps events of a single subscriber: envelopes it published or was sent, and its
+    /// timers, crashes and restarts. Ticks are left out.
+    pub fn with_destination(mut self, destination: &str) -> Self {
+        self.destination = Some(destination.to_string());
+        self
+    }
+
+    /// Keeps the publishes, deliveries and drops of envelopes whose message type contains
+    /// `message_type`, e.g. `Ping` matches `my_crate::Ping`. Other events are left out.
+    pub fn with_message_type(mut self, message_type: &str) -> Self {
+        self.message_type = Some(message_type.to_string());
+        self
+    }
+
+    /// Keeps events at or after `from`.
+    pub fn with_from(mut self, from: SimTime) -> Self {
+        self.from = Some(from);
+        self
+    }
+
+    /// Keeps events at or before `to`.
+    pub fn with_to(mut self, to: SimTime) -> Self {
+        self.to = Some(to);
+        self
+    }
+
+    /// The events of `trace` that pass the filter, in order.
+    pub fn apply<'a>(&self, trace: &'a Trace) -> Vec<&'a TraceEvent> {
+        // Deliveries and drops only refer to their envelope by id
+        let published = published(trace);
+        trace
+            .events
+            .iter()
+            .filter(|event| self.matches(event, &published))
+            .collect()
+    }
+
+    fn matches(&self, event: &TraceEvent, published: &HashMap<u64, Published>) -> bool {
+        let at = event.at();
+        if self.from.is_some_and(|from| at < from) || self.to.is_some_and(|to| at > to) {
+            return false;
+        }
+        let envelope = match event {
+            TraceEvent::Publish { id, .. } | TraceEvent::Deliver { id, .. } | TraceEvent::Drop { id, .. } => {
+                published.get(id)
+            }
+            _ => None,
+        };
+        if let Some(destination) = &self.destination {
+            let matches = match event {
+                TraceEvent::Tick { .. } => false,
+                TraceEvent::Timer { destination: d, .. }
+                | TraceEvent::Crash { destination: d, .. }
+                | TraceEvent::Restart { destination: d, .. } => d == destination,
+                TraceEvent::Publish { source, destination: d, .. } => source == destination || d == destination,
+                TraceEvent::Deliver { destination: d, .. } | TraceEvent::Drop { destination: d, .. } => {
+                    d == destination || envelope.is_some_and(|envelope| &envelope.source == destination)
+                }
+            };
+            if !matches {
+                return false;
+            }
+        }
+        if let Some(message_type) = &self.message_type {
+            return envelope.is_some_and(|envelope| envelope.message_type.contains(message_type.as_str()));
+        }
+        true
+    }
+}
+
+/// What deliveries and drops need to know about their envelope.
+struct Published {
+    source: String,
+    destination: String,
+    message_type: String,
+}
+
+fn published(trace: &Trace) -> HashMap<u64, Published> {
+    trace
+        .events
+        .iter()
+        .filter_map(|event| match event {
+            TraceEvent::Publish {
+                id,
+                source,
+                destination,
+                message_type,
+                ..
+            } => Some((
+                *id,
+                Published {
+                    source: source.clone(),
+                    destination: destination.clone(),
+                    message_type: message_type.clone(),
+                },
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Envelope counts of a single directed link, see [TraceSummary].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub published: usize,
+    pub delivered: usize,
+    pub dropped: usize,
+}
+
+/// Counts of what happened in a [Trace], see [Trace::summary].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceSummary {
+    pub events: usize,
+    /// Envelope counts per (source, destination) link, the source is empty for envelopes
+    /// published from outside. Dead letters are dropped on the link they were sent on, and
+    /// delivered on the link to the dead-letter destination
+    pub links: BTreeMap<(String, String), LinkStats>,
+    /// Dropped envelopes per [crate::message_bus::DropReason], by its Debug name
+    pub drops: BTreeMap<String, usize>,
+    pub ticks: usize,
+    pub timers: usize,
+    pub crashes: usize,
+    pub restarts: usize,
+    /// The time of the first and last event
+    pub span: Option<(SimTime, SimTime)>,
+}
+
+impl std::fmt::Display for TraceSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} events", self.events)?;
+        if let Some((first, last)) = self.span {
+            write!(f, " from {} to {}", first, last)?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{} ticks, {} timers, {} crashes, {} restarts",
+            self.ticks, self.timers, self.crashes, self.restarts
+        )?;
+        for (reason, count) in self.drops.iter() {
+            writeln!(f, "{} dropped ({})", count, reason)?;
+        }
+        for ((source, destination), stats) in self.links.iter() {
+            let source = if source.is_empty() { "(outside)" } else { source };
+            writeln!(
+                f,
+                "{} -> {}: {} published, {} delivered, {} dropped",
+                source, destination, stats.published, stats.delivered, stats.dropped
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Trace {
+    /// Counts the events of the trace, and the envelopes published, delivered and dropped on
+    /// each link.
+    pub fn summary(&self) -> TraceSummary {
+        let published = published(self);
+        let mut summary = TraceSummary {
+            events: self.events.len(),
+            span: self
+                .events
+                .first()
+                .zip(self.events.last())
+                .map(|(first, last)| (first.at(), last.at())),
+            ..TraceSummary::default()
+        };
+        let link = |id: &u64| {
+            let envelope = published.get(id)?;
+            Some((envelope.source.clone(), envelope.destination.clone()))
+        };
+        for event in self.events.iter() {
+            match event {
+                TraceEvent::Tick { .. } => summary.ticks += 1,
+                TraceEvent::Timer { .. } => summary.timers += 1,
+                TraceEvent::Crash { .. } => summary.crashes += 1,
+                TraceEvent::Restart { .. } => summary.restarts += 1,
+                TraceEvent::Publish { source, destination, .. } => {
+                    let key = (source.clone(), destination.clone());
+                    summary.links.entry(key).or_default().published += 1;
+                }
+                TraceEvent::Deliver { id, destination, .. } => {
+                    // Dead letters keep their id, but are delivered somewhere else
+                    if let Some((source, _)) = link(id) {
+                        let key = (source, destination.clone());
+                        summary.links.entry(key).or_default().delivered += 1;
+                    }
+                }
+                TraceEvent::Drop { id, reason, .. } => {
+                    *summary.drops.entry(format!("{:?}", reason)).or_default() += 1;
+                    if let Some(key) = link(id) {
+                        summary.links.entry(key).or_default().dropped += 1;
+                    }
+                }
+            }
+        }
+        summary
+    }
+
+    /// The index of the first event that differs between the two traces (ignoring copies of
+    /// messages kept for replay), or where the shorter trace ends. `None` if they are the same.
+    pub fn first_divergence(&self, other: &Trace) -> Option<usize> {
+        let diverged = self
+            .events
+            .iter()
+            .zip(other.events.iter())
+            .position(|(a, b)| !a.same_as(b));
+        match diverged {
+            Some(i) => Some(i),
+            None if self.events.len() != other.events.len() => {
+                Some(self.events.len().min(other.events.len()))
+            }
+            None => None,
+        }
+    }
+}
+
+impl TraceEvent {
+    /// Whether both events record the same thing. Copies of messages kept for replay are not
+    /// compared, their [crate::message_bus::SerializedMessage] payloads are.
+    pub fn same_as(&self, other: &TraceEvent) -> bool {
+        match (self, other) {
+            (TraceEvent::Tick { at: a }, TraceEvent::Tick { at: b }) => a == b,
+            (
+                TraceEvent::Timer { destination, token, at },
+                TraceEvent::Timer {
+                    destination: d,
+                    token: t,
+                    at: a,
+                },
+            ) => destination == d && token == t && at == a,
+            (
+                TraceEvent::Publish {
+                    id: id_a,
+                    source: source_a,
+                    destination: destination_a,
+                    priority: priority_a,
+                    delay: delay_a,
+                    at: at_a,
+                    message_type: message_type_a,
+                    description: description_a,
+                    payload: payload_a,
+                    message: _,
+                },
+                TraceEvent::Publish {
+                    id: id_b,
+                    source: source_b,
+                    destination: destination_b,
+                    priority: priority_b,
+                    delay: delay_b,
+                    at: at_b,
+                    message_type: message_type_b,
+                    description: description_b,
+                    payload: payload_b,
+                    message: _,
+                },
+            ) => {
+                (id_a, source_a, destination_a, priority_a, delay_a, at_a)
+                    == (id_b, source_b, destination_b, priority_b, delay_b, at_b)
+                    && (message_type_a, description_a, payload_a) == (message_type_b, description_b, payload_b)
+            }
+            (
+                TraceEvent::Deliver { id, destination, at },
+                TraceEvent::Deliver {
+                    id: i,
+                    destination: d,
+                    at: a,
+                },
+            ) => id == i && destination == d && at == a,
+            (
+                TraceEvent::Drop {
+                    id,
+                    destination,
+                    reason,
+                    at,
+                },
+                TraceEvent::Drop {
+                    id: i,
+                    destination: d,
+                    reason: r,
+                    at: a,
+                },
+            ) => id == i && destination == d && reason == r && at == a,
+            (TraceEvent::Crash { destination, at }, TraceEvent::Crash { destination: d, at: a })
+            | (TraceEvent::Restart { destination, at }, TraceEvent::Restart { destination: d, at: a }) => {
+                destination == d && at == a
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A single line, e.g. `0.100000000s deliver #4 to node_1`.
+impl std::fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceEvent::Tick { at } => write!(f, "{} tick", at),
+            TraceEvent::Timer { destination, token, at } => {
+                write!(f, "{} timer {} on {}", at, token, destination)
+            }
+            TraceEvent::Publish {
+                id,
+                source,
+                destination,
+                priority,
+                delay,
+                at,
+                message_type,
+                description,
+                ..
+            } => {
+                let source = if source.is_empty() { "(outside)" } else { source };
+                write!(f, "{} publish #{} {} -> {} {}", at, id, source, destination, message_type)?;
+                if let Some(description) = description {
+                    write!(f, " {}", description)?;
+                }
+                if *priority != 0 {
+                    write!(f, " priority {}", priority)?;
+                }
+                if !delay.is_zero() {
+                    write!(f, " delay {:?}", delay)?;
+                }
+                Ok(())
+            }
+            TraceEvent::Deliver { id, destination, at } => write!(f, "{} deliver #{} to {}", at, id, destination),
+            TraceEvent::Drop {
+                id,
+                destination,
+                reason,
+                at,
+            } => write!(f, "{} drop #{} to {} ({:?})", at, id, destination, reason),
+            TraceEvent::Crash { destination, at } => write!(f, "{} crash {}", at, destination),
+            TraceEvent::Restart { destination, at } => write!(f, "{} restart {}", at, destination),
+        }
+    }
+}
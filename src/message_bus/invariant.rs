@@ -0,0 +1,57 @@
+use crate::message_bus::{SimTime, Subscriber, Subscribers};
+
+type Check = Box<dyn FnMut(&SubscriberView) -> Result<(), String> + Send>;
+
+/// A property that must hold across all subscribers after every step, see
+/// [crate::message_bus::Simulator::with_invariant].
+pub(crate) struct Invariant {
+    pub(crate) name: String,
+    pub(crate) check: Check,
+}
+
+/// A read-only view of the subscribers of a [crate::message_bus::Simulator], handed to
+/// invariants.
+pub struct SubscriberView<'a> {
+    subscribers: &'a Subscribers,
+    time: SimTime,
+}
+
+impl<'a> SubscriberView<'a> {
+    pub(crate) fn new(subscribers: &'a Subscribers, time: SimTime) -> Self {
+        Self { subscribers, time }
+    }
+
+    /// The current virtual time.
+    pub fn time(&self) -> SimTime {
+        self.time
+    }
+
+    /// The subscriber registered under `destination`, if there is one and it is a `T`.
+    pub fn get<T: Subscriber>(&self, destination: &str) -> Option<&'a T> {
+        self.subscribers
+            .get(destination)?
+            .subscriber
+            .downcast_ref::<T>()
+    }
+
+    /// Every subscriber that is a `T` along with its destination, in visiting order. Crashed
+    /// subscribers are included, check [SubscriberView::is_crashed] if that matters.
+    pub fn all<T: Subscriber>(&self) -> impl Iterator<Item = (&'a str, &'a T)> + 'a {
+        self.subscribers.iter().filter_map(|entry| {
+            let subscriber = entry.subscriber.downcast_ref::<T>()?;
+            Some((entry.name.as_str(), subscriber))
+        })
+    }
+
+    /// The destinations of every subscriber, in visiting order.
+    pub fn names(&self) -> impl Iterator<Item = &'a str> + 'a {
+        self.subscribers.iter().map(|entry| entry.name.as_str())
+    }
+
+    /// Whether the subscriber registered under `destination` is crashed.
+    pub fn is_crashed(&self, destination: &str) -> bool {
+        self.subscribers
+            .get(destination)
+            .is_some_and(|entry| entry.crashed)
+    }
+}
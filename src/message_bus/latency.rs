@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::message_bus::SimRng;
+
+/// Decides how long an envelope takes to travel from one subscriber to another in the
+/// [crate::message_bus::Simulator].
+///
+/// Any randomness must come from the provided `rng`, which is derived from the simulation seed.
+pub trait LatencyModel: Send + 'static {
+    fn latency(&mut self, source: &str, destination: &str, rng: &mut SimRng) -> Duration;
+}
+
+/// Every envelope takes exactly the same time to arrive.
+///
+/// `FixedLatency(Duration::ZERO)` is the default, delivering every envelope on the next step.
+pub struct FixedLatency(pub Duration);
+
+impl LatencyModel for FixedLatency {
+    fn latency(&mut self, _source: &str, _destination: &str, _rng: &mut SimRng) -> Duration {
+        self.0
+    }
+}
+
+/// Latency uniformly distributed between `min` and `max` (inclusive).
+pub struct UniformLatency {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl LatencyModel for UniformLatency {
+    fn latency(&mut self, _source: &str, _destination: &str, rng: &mut SimRng) -> Duration {
+        rng.gen_duration(self.min, self.max)
+    }
+}
+
+/// A floor of `min`, plus an exponentially distributed delay with the given `mean`.
+///
+/// This gives the long tail typical of real networks: most envelopes arrive quickly, a few
+/// take much longer.
+pub struct ExponentialLatency {
+    pub min: Duration,
+    pub mean: Duration,
+}
+
+impl LatencyModel for ExponentialLatency {
+    fn latency(&mut self, _source: &str, _destination: &str, rng: &mut SimRng) -> Duration {
+        // Inverse transform sampling, 1 - u is in (0, 1] so ln never sees 0
+        let u = 1.0 - rng.next_f64();
+        let extra = -u.ln() * self.mean.as_secs_f64();
+        self.min + Duration::from_secs_f64(extra.min(u32::MAX as f64))
+    }
+}
+
+/// Uses a specific model for individual directed links, and a default model for the rest.
+pub struct PerLinkLatency {
+    links: HashMap<(String, String), Box<dyn LatencyModel>>,
+    default: Box<dyn LatencyModel>,
+}
+
+impl PerLinkLatency {
+    pub fn new(default: impl LatencyModel) -> Self {
+        Self {
+            links: HashMap::new(),
+            default: Box::new(default),
+        }
+    }
+
+    /// Sets the model for envelopes sent from `source` to `destination`. The reverse direction
+    /// is not affected.
+    pub fn with_link(mut self, source: &str, destination: &str, model: impl LatencyModel) -> Self {
+        self.links
+            .insert((source.to_string(), destination.to_string()), Box::new(model));
+        self
+    }
+}
+
+impl LatencyModel for PerLinkLatency {
+    fn latency(&mut self, source: &str, destination: &str, rng: &mut SimRng) -> Duration {
+        match self.links.get_mut(&(source.to_string(), destination.to_string())) {
+            Some(model) => model.latency(source, destination, rng),
+            None => self.default.latency(source, destination, rng),
+        }
+    }
+}
+
+impl<F> LatencyModel for F
+where
+    F: FnMut(&str, &str, &mut SimRng) -> Duration + Send + 'static,
+{
+    fn latency(&mut self, source: &str, destination: &str, rng: &mut SimRng) -> Duration {
+        self(source, destination, rng)
+    }
+}
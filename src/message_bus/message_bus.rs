@@ -1,16 +1,19 @@
-use std::collections::HashMap;
+use std::any::Any;
 use std::sync::{
     Arc,
     atomic::{AtomicBool, Ordering},
 };
 use std::thread;
 
-use crate::message_bus::{Envelope, Message, PublishHook, NoOpHook};
+use crate::message_bus::{
+    Context, DeadLetters, Envelope, Message, MonotonicClock, NoOpHook, Outcome, PublishHook, Schedule, SimTime, SubscriberOrder,
+    Subscribers, Timers, UnknownDestination,
+};
 
 /// A subscriber must **always** follow these rules to remain deterministic:
-/// - No internal sleeping (wait until `tick()` when `at` has passed)
+/// - No internal sleeping (wait until `tick()` when `at` has passed, or set a timer with [Context::set_timer])
 /// - No async runtime (need to talk to the internet, or a DB? Kick out to another subscriber)
-/// - No random number generation (unless you seed it with the start message)
+/// - No random number generation, other than the seeded [crate::message_bus::SimRng] from [Context::rng]
 /// - Never care about the tick interval, always operate from time deltas
 ///
 /// ## Tips
@@ -24,9 +27,61 @@ use crate::message_bus::{Envelope, Message, PublishHook, NoOpHook};
 /// For example, if you have an io_uring [Subscriber], on [Subscriber::receive] you would enqueue the IO operation,
 /// have a background thread polling for completions, and on [Subscriber::tick] or [Subscriber::receive] you would return any envelopes
 /// destined back to the caller.
-pub trait Subscriber: Send + 'static {
-    fn receive(&mut self, msg: Box<dyn Message>, at: std::time::SystemTime) -> Vec<Envelope>;
-    fn tick(&mut self, at: std::time::SystemTime) -> Vec<Envelope>;
+pub trait Subscriber: Any + Send + 'static {
+    /// Receives a message sent by `source`, the destination name of the sending subscriber
+    /// (empty if published from outside).
+    fn receive(
+        &mut self,
+        ctx: &mut Context,
+        msg: Box<dyn Message>,
+        source: &str,
+        at: SimTime,
+    ) -> Vec<Envelope>;
+    fn tick(&mut self, ctx: &mut Context, at: SimTime) -> Vec<Envelope>;
+
+    /// Called when a timer set with [Context::set_timer] is due, `at` is the time it was set for.
+    fn timer(&mut self, _ctx: &mut Context, _token: u64, _at: SimTime) -> Vec<Envelope> {
+        vec![]
+    }
+
+    /// Called when the [crate::message_bus::Simulator] crashes this subscriber.
+    ///
+    /// Discard anything that would not survive a real crash (i.e. anything not "on disk"). Unless a
+    /// restart factory is registered, this same instance is resumed when the subscriber restarts.
+    fn on_crash(&mut self) {}
+}
+
+impl dyn Subscriber {
+    pub fn as_any(&self) -> &(dyn Any + Send) {
+        self
+    }
+
+    pub fn as_any_mut(&mut self) -> &mut (dyn Any + Send) {
+        self
+    }
+
+    pub fn into_any(self: Box<Self>) -> Box<dyn Any + Send> {
+        self
+    }
+
+    pub fn downcast_ref<T: Subscriber>(&self) -> Option<&T> {
+        self.as_any().downcast_ref::<T>()
+    }
+
+    pub fn downcast_mut<T: Subscriber>(&mut self) -> Option<&mut T> {
+        self.as_any_mut().downcast_mut::<T>()
+    }
+
+    pub fn downcast<T: Subscriber>(self: Box<Self>) -> Result<Box<T>, Box<dyn Subscriber>> {
+        if self.as_any().is::<T>() {
+            let boxed_any = self.into_any();
+            Ok(boxed_any
+                .downcast::<T>()
+                .expect("type check and downcast should succeed"))
+        } else {
+            Err(self)
+        }
+    }
 }
 
 /// Internal no-op envelope used to wake the receiver during shutdown
@@ -35,12 +90,14 @@ struct NopEnvelope;
 impl Message for NopEnvelope {}
 
 pub struct MessageBus<H: PublishHook = NoOpHook> {
-    subscribers: HashMap<String, Box<dyn Subscriber>>,
+    subscribers: Subscribers,
     msg_rxs: Option<Vec<flume::Receiver<Envelope>>>,
     msg_txs: Vec<flume::Sender<Envelope>>,
     tick_interval: std::time::Duration,
     shutdown: Arc<AtomicBool>,
     handle: Option<thread::JoinHandle<()>>,
+    unknown_destination: UnknownDestination,
+    dead_letters: DeadLetters,
     hook: Option<H>,
 }
 
@@ -77,28 +134,73 @@ impl<H: PublishHook> MessageBus<H> {
         let (msg_txs, msg_rxs): (Vec<_>, Vec<_>) = (0..queues).map(|_| flume::unbounded()).unzip();
 
         Self {
-            subscribers: HashMap::new(),
+            subscribers: Subscribers::new(SubscriberOrder::default()),
             msg_rxs: Some(msg_rxs),
             msg_txs,
             tick_interval,
             shutdown: Arc::new(AtomicBool::new(false)),
             handle: None,
+            unknown_destination: UnknownDestination::default(),
+            dead_letters: DeadLetters::default(),
             hook: Some(hook),
         }
     }
 
+    /// Sets the seed that each subscriber's [crate::message_bus::SimRng] (see [Context::rng]) is
+    /// derived from. Defaults to 0.
+    ///
+    /// Using the same seed as a [crate::message_bus::Simulator] gives every subscriber the
+    /// same random stream in both.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.subscribers.set_seed(seed);
+        self
+    }
+
+    /// Sets the order in which subscribers are ticked, see [SubscriberOrder].
+    pub fn with_subscriber_order(mut self, order: SubscriberOrder) -> Self {
+        self.subscribers.set_order(order);
+        self
+    }
+
+    /// Sets what happens to envelopes sent to a destination without a subscriber, see
+    /// [UnknownDestination]. Defaults to [UnknownDestination::Drop].
+    ///
+    /// With [UnknownDestination::Panic], the processing thread panics and stops.
+    pub fn with_unknown_destination(mut self, policy: UnknownDestination) -> Self {
+        self.unknown_destination = policy;
+        self
+    }
+
+    /// Sets what happens to envelopes that will never be delivered, see [DeadLetters]. The
+    /// MessageBus only drops envelopes to unknown destinations, see
+    /// [MessageBus::with_unknown_destination].
+    pub fn with_dead_letters(mut self, dead_letters: DeadLetters) -> Self {
+        self.dead_letters = dead_letters;
+        self
+    }
+
     pub fn start(&mut self) -> Vec<flume::Sender<Envelope>> {
         println!("Starting MessageBus");
         // launch thread to handle message sending
         let rx = self.msg_rxs.take().expect("MessageBus already started");
-        let tx = self.msg_txs.clone();
         let tick_interval = self.tick_interval;
         let shutdown = self.shutdown.clone();
-        let subscribers = std::mem::take(&mut self.subscribers);
-        let hook = self.hook.take().expect("MessageBus already started");
+        let worker = Worker {
+            txs: self.msg_txs.clone(),
+            subscribers: std::mem::replace(
+                &mut self.subscribers,
+                Subscribers::new(SubscriberOrder::default()),
+            ),
+            timers: Timers::default(),
+            delayed: Schedule::default(),
+            next_id: 1,
+            unknown_destination: self.unknown_destination.clone(),
+            dead_letters: self.dead_letters.clone(),
+            hook: self.hook.take().expect("MessageBus already started"),
+        };
 
         let handle = thread::spawn(move || {
-            Self::process_messages(rx, tx, subscribers, tick_interval, shutdown, hook);
+            Self::process_messages(rx, tick_interval, shutdown, worker);
         });
 
         self.handle = Some(handle);
@@ -116,25 +218,19 @@ impl<H: PublishHook> MessageBus<H> {
 
     fn process_messages(
         rxs: Vec<flume::Receiver<Envelope>>,
-        txs: Vec<flume::Sender<Envelope>>,
-        mut subscribers: HashMap<String, Box<dyn Subscriber>>,
         tick_interval: std::time::Duration,
         shutdown: Arc<AtomicBool>,
-        hook: H,
+        mut worker: Worker<H>,
     ) {
         println!("Processing messages");
-        let start_time = std::time::SystemTime::now();
+        let clock = MonotonicClock::new();
+        let start_time = clock.now();
         let mut next_tick = start_time + tick_interval;
 
         // Handle initial tick
-        for subscriber in subscribers.values_mut() {
-            let envelopes = subscriber.tick(start_time);
-            for envelope in envelopes {
-                hook.on_publish(&envelope, start_time);
-                let priority = envelope.priority.min(txs.len() - 1);
-                txs[priority].send(envelope).unwrap();
-            }
-        }
+        worker.hook.on_step_start(start_time);
+        worker.tick_all(start_time);
+        worker.hook.on_step_end(clock.now());
 
         loop {
             println!("Processing messages loop");
@@ -142,25 +238,29 @@ impl<H: PublishHook> MessageBus<H> {
                 break;
             }
             // If we've passed the scheduled tick time, catch up (handle multiple if needed)
-            let now = std::time::SystemTime::now();
+            let now = clock.now();
             if now >= next_tick {
-                while next_tick <= std::time::SystemTime::now() {
-                    let at = next_tick;
-                    for (name, subscriber) in subscribers.iter_mut() {
-                        println!("Ticking {}", name);
-                        let envelopes = subscriber.tick(at);
-                        for envelope in envelopes {
-                            hook.on_publish(&envelope, at);
-                            let priority = envelope.priority.min(txs.len() - 1);
-                            txs[priority].send(envelope).unwrap();
-                        }
-                    }
+                worker.hook.on_step_start(now);
+                while next_tick <= clock.now() {
+                    worker.tick_all(next_tick);
                     next_tick += tick_interval;
                 }
+                worker.hook.on_step_end(clock.now());
+                continue;
+            }
+
+            // Fire any timers and release any delayed envelopes that are due, they wake us up
+            // just like ticks do
+            if worker.next_wakeup().is_some_and(|at| at <= now) {
+                worker.hook.on_step_start(now);
+                worker.fire_timers(now);
+                worker.release_delayed(now);
+                worker.hook.on_step_end(clock.now());
                 continue;
             }
+            let wake_at = worker.next_wakeup().map_or(next_tick, |at| at.min(next_tick));
 
-            let timeout = next_tick.duration_since(now).unwrap_or(tick_interval); // if time moves backwards, or we don't make the next tick, we set to the default duration
+            let timeout = wake_at.checked_duration_since(now).unwrap_or(tick_interval); // if time moves backwards, or we don't make the next tick, we set to the default duration
             // TODO: warn log if time moves backwards or takes too long
 
             // First, try all queues in decreasing priority order (non-blocking)
@@ -187,17 +287,13 @@ impl<H: PublishHook> MessageBus<H> {
             }
 
             // Process the envelope if we got one
-            if let Some(envelope) = envelope_opt {
-                let Some(subscriber) = subscribers.get_mut(&envelope.destination) else {
-                    continue;
-                };
-                let at = std::time::SystemTime::now();
-                let envelopes = subscriber.receive(envelope.message, at);
-                for envelope in envelopes {
-                    hook.on_publish(&envelope, at);
-                    let priority = envelope.priority.min(txs.len() - 1);
-                    txs[priority].send(envelope).unwrap();
-                }
+            if let Some(envelope) = envelope_opt
+                && envelope.message.downcast_ref::<NopEnvelope>().is_none()
+            {
+                let now = clock.now();
+                worker.hook.on_step_start(now);
+                worker.receive(envelope, now);
+                worker.hook.on_step_end(clock.now());
             }
         }
     }
@@ -209,11 +305,7 @@ impl<H: PublishHook> MessageBus<H> {
         }
 
         // Wake the worker if it's blocked on recv_timeout by publishing a nop (high priority)
-        let _ = self.msg_txs[self.msg_txs.len() - 1].send(Envelope {
-            message: Box::new(NopEnvelope),
-            destination: "".to_string(),
-            priority: 0,
-        });
+        let _ = self.msg_txs[self.msg_txs.len() - 1].send(Envelope::new("", NopEnvelope));
 
         // Join the worker thread if present
         if let Some(handle) = self.handle.take() {
@@ -222,6 +314,117 @@ impl<H: PublishHook> MessageBus<H> {
     }
 }
 
+/// The state owned by the [MessageBus] processing thread.
+struct Worker<H: PublishHook> {
+    txs: Vec<flume::Sender<Envelope>>,
+    subscribers: Subscribers,
+    timers: Timers,
+    /// Envelopes held until their [Envelope::delay] passes
+    delayed: Schedule<Envelope>,
+    /// Id of the next published envelope
+    next_id: u64,
+    unknown_destination: UnknownDestination,
+    dead_letters: DeadLetters,
+    hook: H,
+}
+
+impl<H: PublishHook> Worker<H> {
+    fn tick_all(&mut self, at: SimTime) {
+        self.hook.on_tick(at);
+        for name in self.subscribers.running_names() {
+            println!("Ticking {}", name);
+            let outcome = self.subscribers.get_mut(&name).unwrap().tick(at);
+            self.handle(&name, outcome, at);
+        }
+    }
+
+    /// Fires every timer due at or before `now`, passing each the time it was set for.
+    fn fire_timers(&mut self, now: SimTime) {
+        for (_, (name, token, at)) in self.timers.take_due(now) {
+            let Some(subscriber) = self.subscribers.get_mut(&name) else {
+                continue;
+            };
+            self.hook.on_timer(&name, token, at);
+            let outcome = subscriber.timer(token, at);
+            self.handle(&name, outcome, at);
+        }
+    }
+
+    /// The next time a timer or delayed envelope is due.
+    fn next_wakeup(&self) -> Option<SimTime> {
+        [self.timers.next(), self.delayed.next()].into_iter().flatten().min()
+    }
+
+    /// Queues every delayed envelope due at or before `now` for delivery.
+    fn release_delayed(&mut self, now: SimTime) {
+        for (_, envelope) in self.delayed.take_due(now) {
+            self.send(envelope);
+        }
+    }
+
+    fn send(&mut self, envelope: Envelope) {
+        let priority = envelope.priority.min(self.txs.len() - 1);
+        self.txs[priority].send(envelope).unwrap();
+    }
+
+    /// Stamps an envelope published by `source` (empty if published from outside) with its source
+    /// and a fresh id.
+    fn stamp(&mut self, source: &str, envelope: &mut Envelope) {
+        envelope.source = source.to_string();
+        envelope.id = self.next_id;
+        self.next_id += 1;
+    }
+
+    fn receive(&mut self, mut envelope: Envelope, at: SimTime) {
+        // Envelopes published from outside are stamped when they first reach the worker
+        if envelope.id == 0 {
+            self.stamp("", &mut envelope);
+            self.hook.on_publish(&envelope, at);
+        }
+        // Envelopes published from outside with a delay are held here
+        if !envelope.delay.is_zero() {
+            let delay = std::mem::take(&mut envelope.delay);
+            self.delayed.push(at + delay, envelope);
+            return;
+        }
+        let Some(envelope) =
+            self.unknown_destination
+                .route(&self.subscribers, &self.dead_letters, &self.hook, envelope, at)
+        else {
+            return;
+        };
+        let subscriber = self.subscribers.get_mut(&envelope.destination).unwrap();
+        let name = subscriber.name.clone();
+        self.hook.on_deliver(&envelope, at);
+        let outcome = subscriber.receive(envelope, at);
+        self.handle(&name, outcome, at);
+    }
+
+    /// Stamps the envelopes emitted by `source` and queues them for delivery, and applies
+    /// everything else it produced.
+    fn handle(&mut self, source: &str, outcome: Outcome, at: SimTime) {
+        for mut envelope in outcome.envelopes {
+            self.stamp(source, &mut envelope);
+            self.hook.on_publish(&envelope, at);
+            if envelope.delay.is_zero() {
+                self.send(envelope);
+            } else {
+                let delay = std::mem::take(&mut envelope.delay);
+                self.delayed.push(at + delay, envelope);
+            }
+        }
+        for (timer_at, token) in outcome.timers {
+            self.timers.push(timer_at, (source.to_string(), token, timer_at));
+        }
+        for (destination, subscriber) in outcome.spawned {
+            // The timers of a replaced subscriber are cancelled
+            if self.subscribers.insert(destination.clone(), subscriber).is_some() {
+                self.timers.extract(|(name, _, _)| *name == destination);
+            }
+        }
+    }
+}
+
 impl<H: PublishHook> Drop for MessageBus<H> {
     fn drop(&mut self) {
         self.stop();
@@ -1,7 +1,42 @@
+pub mod action;
+pub mod clock;
+pub mod context;
+pub mod crash;
+pub mod dead_letter;
 pub mod envelope;
+pub mod faults;
+pub mod inspect;
+pub mod invariant;
+pub mod latency;
+#[allow(clippy::module_inception)]
 pub mod message_bus;
+mod network;
+pub mod rng;
+pub mod scenario;
 pub mod simulator;
+mod schedule;
+pub mod subscribers;
+pub mod time;
+pub mod trace;
+pub mod trace_file;
 
+pub use action::*;
+pub use clock::*;
+pub use context::*;
+pub use crash::*;
+pub use dead_letter::*;
 pub use envelope::*;
+pub use faults::*;
+pub use inspect::*;
+pub use invariant::*;
+pub use latency::*;
 pub use message_bus::*;
+pub(crate) use network::*;
+pub use rng::*;
+pub use scenario::*;
 pub use simulator::*;
+pub(crate) use schedule::*;
+pub use subscribers::*;
+pub use time::*;
+pub use trace::*;
+pub use trace_file::*;
@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::message_bus::{
+    DeadLetters, DropReason, Envelope, Fault, FixedLatency, LatencyModel, NetworkFaults, PublishHook, Schedule, SimAction, SimRng,
+    SimTime, SimulatorEvent,
+};
+
+/// The simulated network between the subscribers of a [crate::message_bus::Simulator].
+pub(crate) struct Network {
+    latency: Box<dyn LatencyModel>,
+    latency_rng: SimRng,
+    faults: Option<NetworkFaults>,
+    fault_rng: SimRng,
+    /// Partition group of each partitioned destination
+    groups: HashMap<String, usize>,
+    /// Directed (source, destination) links that are cut
+    cut: HashSet<(String, String)>,
+    /// Envelopes waiting for their delivery time
+    in_flight: Schedule<Envelope>,
+    /// Id of the next published envelope
+    next_id: u64,
+    /// How many envelopes were published on each (source, destination) link
+    link_seqs: HashMap<(String, String), u64>,
+    dead_letters: DeadLetters,
+    /// Faults injected so far (including the actions the simulator logs), if recording
+    log: Option<Vec<Fault>>,
+    /// Envelope faults to inject by (source, destination, seq), instead of drawing them from the
+    /// fault rates
+    script: Option<HashMap<(String, String, u64), Vec<Fault>>>,
+}
+
+/// The faults injected into a single envelope.
+#[derive(Default)]
+struct EnvelopeFaults {
+    drop: bool,
+    duplicate: bool,
+    delay: Option<std::time::Duration>,
+}
+
+impl Network {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self {
+            latency: Box::new(FixedLatency(std::time::Duration::ZERO)),
+            latency_rng: SimRng::derive(seed, "dsim::latency"),
+            faults: None,
+            fault_rng: SimRng::derive(seed, "dsim::faults"),
+            groups: HashMap::new(),
+            cut: HashSet::new(),
+            in_flight: Schedule::default(),
+            next_id: 1,
+            link_seqs: HashMap::new(),
+            dead_letters: DeadLetters::default(),
+            log: None,
+            script: None,
+        }
+    }
+
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        self.latency_rng = SimRng::derive(seed, "dsim::latency");
+        self.fault_rng = SimRng::derive(seed, "dsim::faults");
+    }
+
+    pub(crate) fn set_faults(&mut self, faults: NetworkFaults) {
+        self.faults = Some(faults);
+    }
+
+    pub(crate) fn set_latency(&mut self, latency: Box<dyn LatencyModel>) {
+        self.latency = latency;
+    }
+
+    pub(crate) fn set_dead_letters(&mut self, dead_letters: DeadLetters) {
+        self.dead_letters = dead_letters;
+    }
+
+    pub(crate) fn dead_letters(&self) -> &DeadLetters {
+        &self.dead_letters
+    }
+
+    /// Injects exactly the envelope faults in `faults` from now on, ignoring the fault rates.
+    pub(crate) fn set_script(&mut self, faults: &[Fault]) {
+        let mut script: HashMap<(String, String, u64), Vec<Fault>> = HashMap::new();
+        for fault in faults {
+            if let Fault::Drop { source, destination, seq, .. }
+            | Fault::Duplicate { source, destination, seq, .. }
+            | Fault::Delay { source, destination, seq, .. } = fault
+            {
+                script
+                    .entry((source.clone(), destination.clone(), *seq))
+                    .or_default()
+                    .push(fault.clone());
+            }
+        }
+        self.script = Some(script);
+    }
+
+    /// Starts recording the faults injected from now on.
+    pub(crate) fn record_faults(&mut self) {
+        self.log.get_or_insert_with(Vec::new);
+    }
+
+    pub(crate) fn log_fault(&mut self, fault: Fault) {
+        if let Some(log) = &mut self.log {
+            log.push(fault);
+        }
+    }
+
+    /// The faults injected so far, empty unless recording.
+    pub(crate) fn faults(&self) -> &[Fault] {
+        self.log.as_deref().unwrap_or_default()
+    }
+
+    /// Decides which faults to inject into an envelope, from the script if there is one,
+    /// otherwise drawn from the fault rates.
+    fn decide_faults(&mut self, source: &str, envelope: &Envelope, seq: u64) -> EnvelopeFaults {
+        let mut decided = EnvelopeFaults::default();
+        if let Some(script) = &self.script {
+            let key = (source.to_string(), envelope.destination.clone(), seq);
+            for fault in script.get(&key).into_iter().flatten() {
+                match fault {
+                    Fault::Drop { .. } => decided.drop = true,
+                    Fault::Duplicate { .. } => decided.duplicate = true,
+                    Fault::Delay { by, .. } => decided.delay = Some(*by),
+                    Fault::Action { .. } => {}
+                }
+            }
+            return decided;
+        }
+        let Some(faults) = &self.faults else {
+            return decided;
+        };
+        let rates = faults.rates_for(source, envelope);
+        if self.fault_rng.gen_bool(rates.drop) {
+            decided.drop = true;
+            return decided;
+        }
+        decided.duplicate = self.fault_rng.gen_bool(rates.duplicate);
+        if self.fault_rng.gen_bool(rates.reorder) {
+            decided.delay = Some(
+                self.fault_rng
+                    .gen_duration(std::time::Duration::from_nanos(1), faults.reorder_delay()),
+            );
+        }
+        decided
+    }
+
+    /// Drops an envelope that will never be delivered, queueing any resulting dead letter in the
+    /// `ready` queues.
+    pub(crate) fn drop_envelope<H: PublishHook>(
+        &self,
+        hook: &H,
+        envelope: Envelope,
+        reason: DropReason,
+        at: SimTime,
+        ready: &mut [VecDeque<SimulatorEvent>],
+    ) {
+        if let Some(dead_letter) = self.dead_letters.drop(hook, envelope, reason, at) {
+            let priority = dead_letter.priority.min(ready.len() - 1);
+            ready[priority].push_back(SimulatorEvent::Envelope(dead_letter, at));
+        }
+    }
+
+    /// Stamps an envelope published by `source` (empty if published from outside) with its source
+    /// and a fresh id.
+    pub(crate) fn stamp(&mut self, source: &str, envelope: &mut Envelope) {
+        envelope.source = source.to_string();
+        envelope.id = self.next_id;
+        self.next_id += 1;
+    }
+
+    /// Continues handing out ids after `id`, to follow the ids of a recorded run.
+    pub(crate) fn skip_ids_to(&mut self, id: u64) {
+        self.next_id = self.next_id.max(id + 1);
+    }
+
+    /// Applies a change to the network topology. Other actions are ignored.
+    pub(crate) fn apply(&mut self, action: &SimAction) {
+        match action {
+            SimAction::Partition(groups) => {
+                self.groups = groups
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, group)| group.iter().map(move |destination| (destination.clone(), i)))
+                    .collect();
+            }
+            SimAction::Heal => {
+                self.groups.clear();
+                self.cut.clear();
+            }
+            SimAction::CutLink { source, destination } => {
+                self.cut.insert((source.clone(), destination.clone()));
+            }
+            SimAction::RestoreLink { source, destination } => {
+                self.cut.remove(&(source.clone(), destination.clone()));
+            }
+            SimAction::Crash(_) | SimAction::Restart(_) => {}
+        }
+    }
+
+    /// Whether envelopes from `source` can currently reach `destination`.
+    pub(crate) fn reachable(&self, source: &str, destination: &str) -> bool {
+        if let (Some(a), Some(b)) = (self.groups.get(source), self.groups.get(destination))
+            && a != b
+        {
+            return false;
+        }
+        !self.cut.contains(&(source.to_string(), destination.to_string()))
+    }
+
+    /// Publishes the envelopes emitted by `source` at time `at`, stamping them, dropping
+    /// envelopes that can't reach their destination and applying any network faults.
+    ///
+    /// Envelopes without latency or [Envelope::delay] go straight to the `ready` queues (for the
+    /// next step), the rest are held until [Network::deliver_due] reaches their delivery time.
+    pub(crate) fn publish<H: PublishHook>(
+        &mut self,
+        hook: &H,
+        source: &str,
+        envelopes: Vec<Envelope>,
+        at: SimTime,
+        ready: &mut [VecDeque<SimulatorEvent>],
+    ) {
+        for mut envelope in envelopes {
+            self.stamp(source, &mut envelope);
+            let seq = self
+                .link_seqs
+                .entry((source.to_string(), envelope.destination.clone()))
+                .or_default();
+            *seq += 1;
+            let seq = *seq;
+            hook.on_publish(&envelope, at);
+            if !self.reachable(source, &envelope.destination) {
+                self.drop_envelope(hook, envelope, DropReason::Partitioned, at, ready);
+                continue;
+            }
+            let faults = self.decide_faults(source, &envelope, seq);
+            if faults.drop {
+                self.log_fault(Fault::Drop {
+                    source: source.to_string(),
+                    destination: envelope.destination.clone(),
+                    seq,
+                    at,
+                });
+                self.drop_envelope(hook, envelope, DropReason::Fault, at, ready);
+                continue;
+            }
+            if faults.duplicate
+                && let Some(message) = envelope.message.try_clone()
+            {
+                self.log_fault(Fault::Duplicate {
+                    source: source.to_string(),
+                    destination: envelope.destination.clone(),
+                    seq,
+                    at,
+                });
+                let duplicate = Envelope {
+                    message,
+                    priority: envelope.priority,
+                    destination: envelope.destination.clone(),
+                    source: envelope.source.clone(),
+                    delay: envelope.delay,
+                    // The same envelope, delivered twice
+                    id: envelope.id,
+                };
+                self.send(source, duplicate, at, std::time::Duration::ZERO, ready);
+            }
+            let mut extra = std::time::Duration::ZERO;
+            if let Some(by) = faults.delay {
+                self.log_fault(Fault::Delay {
+                    source: source.to_string(),
+                    destination: envelope.destination.clone(),
+                    seq,
+                    by,
+                    at,
+                });
+                extra = by;
+            }
+            self.send(source, envelope, at, extra, ready);
+        }
+    }
+
+    fn send(
+        &mut self,
+        source: &str,
+        envelope: Envelope,
+        at: SimTime,
+        extra: std::time::Duration,
+        ready: &mut [VecDeque<SimulatorEvent>],
+    ) {
+        let delay = self
+            .latency
+            .latency(source, &envelope.destination, &mut self.latency_rng)
+            + extra
+            + envelope.delay;
+        if delay.is_zero() {
+            let priority = envelope.priority.min(ready.len() - 1);
+            ready[priority].push_back(SimulatorEvent::Envelope(envelope, at));
+        } else {
+            self.in_flight.push(at + delay, envelope);
+        }
+    }
+
+    /// Drops every held envelope headed to `destination`.
+    pub(crate) fn drop_inbound<H: PublishHook>(
+        &mut self,
+        hook: &H,
+        destination: &str,
+        reason: DropReason,
+        at: SimTime,
+        ready: &mut [VecDeque<SimulatorEvent>],
+    ) {
+        let dropped = self
+            .in_flight
+            .extract(|envelope| envelope.destination == destination);
+        for (_, envelope) in dropped {
+            self.drop_envelope(hook, envelope, reason, at, ready);
+        }
+    }
+
+    /// The delivery time of the next held envelope.
+    pub(crate) fn next_delivery(&self) -> Option<SimTime> {
+        self.in_flight.next()
+    }
+
+    /// Moves every held envelope due at or before `now` to the `ready` queues, in delivery
+    /// time order.
+    pub(crate) fn deliver_due(&mut self, now: SimTime, ready: &mut [VecDeque<SimulatorEvent>]) {
+        for (deliver_at, envelope) in self.in_flight.take_due(now) {
+            let priority = envelope.priority.min(ready.len() - 1);
+            ready[priority].push_back(SimulatorEvent::Envelope(envelope, deliver_at));
+        }
+    }
+}
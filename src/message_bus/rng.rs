@@ -0,0 +1,95 @@
+/// A small, fast, deterministic pseudo random number generator (xoshiro256**).
+///
+/// The output sequence only depends on the seed, and will never change between versions of this
+/// crate, so a seed is enough to reproduce a simulation run.
+#[derive(Debug, Clone)]
+pub struct SimRng {
+    state: [u64; 4],
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        // Expand the seed with splitmix64 so that similar seeds produce unrelated streams
+        let mut sm = seed;
+        let mut state = [0u64; 4];
+        for word in state.iter_mut() {
+            *word = splitmix64(&mut sm);
+        }
+        Self { state }
+    }
+
+    /// Derives an independent generator for a named stream (e.g. a subscriber) from a seed.
+    ///
+    /// The stream only depends on the seed and the label, so adding or removing other streams
+    /// does not change the numbers a given stream produces.
+    pub fn derive(seed: u64, label: &str) -> Self {
+        // FNV-1a
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in label.as_bytes() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        let mut mixed = seed ^ hash;
+        Self::new(splitmix64(&mut mixed))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.state[1] << 17;
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+        self.state[2] ^= t;
+        self.state[3] = self.state[3].rotate_left(45);
+        result
+    }
+
+    /// Returns a float uniformly distributed in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+
+    /// Returns a number uniformly distributed in `[low, high)`. Returns `low` if the range is empty.
+    pub fn gen_range(&mut self, low: u64, high: u64) -> u64 {
+        if high <= low {
+            return low;
+        }
+        let span = high - low;
+        // Lemire's multiply-shift, rejecting the biased zone
+        loop {
+            let m = (self.next_u64() as u128) * (span as u128);
+            if (m as u64) >= span.wrapping_neg() % span {
+                return low + (m >> 64) as u64;
+            }
+        }
+    }
+
+    /// Returns `true` with probability `p`.
+    pub fn gen_bool(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        if p >= 1.0 {
+            return true;
+        }
+        self.next_f64() < p
+    }
+
+    /// Returns a duration uniformly distributed in `[min, max]`.
+    pub fn gen_duration(&mut self, min: std::time::Duration, max: std::time::Duration) -> std::time::Duration {
+        if max <= min {
+            return min;
+        }
+        let span = (max - min).as_nanos().min(u64::MAX as u128 - 1) as u64;
+        min + std::time::Duration::from_nanos(self.gen_range(0, span + 1))
+    }
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
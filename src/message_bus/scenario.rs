@@ -0,0 +1,164 @@
+use std::io::Read;
+use std::time::Duration;
+
+use crate::message_bus::{SimAction, format_duration, parse_duration};
+
+/// A scripted sequence of [SimAction]s, each at an offset from the start of the simulation,
+/// see [crate::message_bus::Simulator::with_scenario].
+///
+/// Scenarios can be built in code with [Scenario::at], or written as text with one action per
+/// line (or separated by `;`), and `#` starting a comment:
+///
+/// ```text
+/// at 5s partition a,b | c
+/// at 8s crash b
+/// at 10s restart b; at 12s heal
+/// at 500ms cut a -> b
+/// at 1.5s restore a -> b
+/// ```
+///
+/// Offsets take a `ns`, `us`, `ms`, `s`, `m` or `h` unit. Partition groups are separated by `|`
+/// and may be written in braces, e.g. `{a,b} | {c}`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scenario {
+    /// Actions and their offset from the start of the simulation, in the order they were added
+    pub actions: Vec<(Duration, SimAction)>,
+}
+
+impl Scenario {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an action `offset` after the start of the simulation.
+    pub fn at(mut self, offset: Duration, action: SimAction) -> Self {
+        self.actions.push((offset, action));
+        self
+    }
+
+    /// Appends the actions of `other`, to compose scenarios.
+    pub fn with(mut self, other: Scenario) -> Self {
+        self.actions.extend(other.actions);
+        self
+    }
+
+    /// Parses a scenario from its text form.
+    pub fn parse(text: &str) -> std::io::Result<Scenario> {
+        let mut scenario = Scenario::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            for statement in line.split(';') {
+                if statement.trim().is_empty() {
+                    continue;
+                }
+                let (offset, action) = parse_statement(statement)
+                    .map_err(|reason| invalid(format!("line {}: {}", i + 1, reason)))?;
+                scenario.actions.push((offset, action));
+            }
+        }
+        Ok(scenario)
+    }
+
+    /// Reads a scenario in its text form, e.g. from a file shared by several tests.
+    pub fn read_from(mut reader: impl Read) -> std::io::Result<Scenario> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        Self::parse(&text)
+    }
+
+    /// Loads a scenario file in its text form.
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Scenario> {
+        Self::read_from(std::fs::File::open(path)?)
+    }
+}
+
+impl std::str::FromStr for Scenario {
+    type Err = std::io::Error;
+
+    fn from_str(text: &str) -> std::io::Result<Scenario> {
+        Scenario::parse(text)
+    }
+}
+
+/// Writes the text form, one action per line, which [Scenario::parse] reads back.
+impl std::fmt::Display for Scenario {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (offset, action) in self.actions.iter() {
+            write!(f, "at {} ", format_duration(*offset))?;
+            match action {
+                SimAction::Partition(groups) => {
+                    let groups: Vec<String> = groups.iter().map(|group| group.join(",")).collect();
+                    writeln!(f, "partition {}", groups.join(" | "))?
+                }
+                SimAction::Heal => writeln!(f, "heal")?,
+                SimAction::CutLink { source, destination } => writeln!(f, "cut {} -> {}", source, destination)?,
+                SimAction::RestoreLink { source, destination } => {
+                    writeln!(f, "restore {} -> {}", source, destination)?
+                }
+                SimAction::Crash(destination) => writeln!(f, "crash {}", destination)?,
+                SimAction::Restart(destination) => writeln!(f, "restart {}", destination)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason.into())
+}
+
+/// Parses `at <offset> <action> <arguments>`.
+fn parse_statement(statement: &str) -> Result<(Duration, SimAction), String> {
+    let mut words = statement.split_whitespace();
+    if words.next() != Some("at") {
+        return Err(format!("expected `at <offset> <action>`, found {:?}", statement.trim()));
+    }
+    let offset = parse_duration(words.next().ok_or("missing offset")?)?;
+    let action = words.next().ok_or("missing action")?;
+    let rest = words.collect::<Vec<_>>().join(" ");
+    let name = |rest: &str| match rest.split_whitespace().collect::<Vec<_>>()[..] {
+        [name] => Ok(name.to_string()),
+        _ => Err(format!("expected a single destination, found {:?}", rest)),
+    };
+    let link = |rest: &str| match rest.split("->").map(str::trim).collect::<Vec<_>>()[..] {
+        [source, destination] if !source.is_empty() && !destination.is_empty() => {
+            Ok((source.to_string(), destination.to_string()))
+        }
+        _ => Err(format!("expected `<source> -> <destination>`, found {:?}", rest)),
+    };
+    let action = match action {
+        "partition" => {
+            let groups: Vec<Vec<String>> = rest
+                .split('|')
+                .map(|group| {
+                    group
+                        .trim()
+                        .trim_start_matches('{')
+                        .trim_end_matches('}')
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .collect();
+            if groups.iter().any(Vec::is_empty) {
+                return Err(format!("expected groups like `a,b | c`, found {:?}", rest));
+            }
+            SimAction::Partition(groups)
+        }
+        "heal" if rest.is_empty() => SimAction::Heal,
+        "cut" => {
+            let (source, destination) = link(&rest)?;
+            SimAction::CutLink { source, destination }
+        }
+        "restore" => {
+            let (source, destination) = link(&rest)?;
+            SimAction::RestoreLink { source, destination }
+        }
+        "crash" => SimAction::Crash(name(&rest)?),
+        "restart" => SimAction::Restart(name(&rest)?),
+        _ => return Err(format!("unknown action {:?}", statement.trim())),
+    };
+    Ok((offset, action))
+}
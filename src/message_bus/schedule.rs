@@ -0,0 +1,93 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::message_bus::SimTime;
+
+/// An item due at a virtual (or real) time.
+struct Scheduled<T> {
+    at: SimTime,
+    /// Tie breaker so items due at the same time come out in the order they were pushed
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Scheduled<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Scheduled<T> {}
+
+impl<T> PartialOrd for Scheduled<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Scheduled<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed, so the BinaryHeap pops the earliest item first
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+/// Items ordered by due time, used for envelopes in flight and subscriber timers.
+pub(crate) struct Schedule<T> {
+    heap: BinaryHeap<Scheduled<T>>,
+    next_seq: u64,
+}
+
+impl<T> Default for Schedule<T> {
+    fn default() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+}
+
+impl<T> Schedule<T> {
+    pub(crate) fn push(&mut self, at: SimTime, item: T) {
+        self.heap.push(Scheduled {
+            at,
+            seq: self.next_seq,
+            item,
+        });
+        self.next_seq += 1;
+    }
+
+    /// The due time of the next item.
+    pub(crate) fn next(&self) -> Option<SimTime> {
+        self.heap.peek().map(|scheduled| scheduled.at)
+    }
+
+    /// Removes and returns every item due at or before `now`, in order.
+    pub(crate) fn take_due(&mut self, now: SimTime) -> Vec<(SimTime, T)> {
+        let mut due = vec![];
+        while self.heap.peek().is_some_and(|scheduled| scheduled.at <= now) {
+            let scheduled = self.heap.pop().unwrap();
+            due.push((scheduled.at, scheduled.item));
+        }
+        due
+    }
+
+    /// Removes and returns every item matching `predicate`, in order.
+    pub(crate) fn extract(&mut self, mut predicate: impl FnMut(&T) -> bool) -> Vec<(SimTime, T)> {
+        let (mut extracted, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.heap)
+            .into_iter()
+            .partition(|scheduled| predicate(&scheduled.item));
+        self.heap = kept.into();
+        // Descending by the reversed ordering is earliest first
+        extracted.sort_by(|a, b| b.cmp(a));
+        extracted
+            .into_iter()
+            .map(|scheduled| (scheduled.at, scheduled.item))
+            .collect()
+    }
+}
+
+/// Pending subscriber timers, as `(destination, token, at)`. `at` is the time the timer was set
+/// for on the subscriber's own clock, passed back as is rather than converted back from the due
+/// time.
+pub(crate) type Timers = Schedule<(String, u64, SimTime)>;
@@ -1,17 +1,85 @@
+use std::cell::RefCell;
 use std::collections::{HashMap, VecDeque};
 
-use crate::message_bus::{Envelope, Subscriber, PublishHook, NoOpHook};
+use crate::message_bus::{
+    Clock, DeadLetter, DeadLetters, DropReason, Envelope, Fault, FaultSchedule, Invariant, LatencyModel, Network, NetworkFaults, NoOpHook, Outcome, PublishHook, RandomClocks, RandomCrashes,
+    Recorder, Scenario, SimAction, SimRng, SimTime, Subscriber, SubscriberOrder, SubscriberView, Subscribers, Timers, Trace, TraceEvent,
+    UnknownDestination,
+};
+
+/// Creates a fresh subscriber instance when a crashed subscriber restarts.
+pub type RestartFactory = Box<dyn FnMut() -> Box<dyn Subscriber> + Send>;
 
 pub enum SimulatorEvent {
-    Envelope(Envelope, std::time::SystemTime),
-    Tick(std::time::SystemTime),
+    Envelope(Envelope, SimTime),
+    Tick(SimTime),
 }
 
 pub struct Simulator<H: PublishHook = NoOpHook> {
-    subscribers: HashMap<String, Box<dyn Subscriber>>,
+    subscribers: Subscribers,
     events: Vec<VecDeque<SimulatorEvent>>,
-    time: std::time::SystemTime,
-    hook: H,
+    network: Network,
+    timers: Timers,
+    /// Actions to apply at a virtual time, sorted by time (ties in scheduling order)
+    scheduled: VecDeque<(SimTime, SimAction)>,
+    factories: HashMap<String, RestartFactory>,
+    crashes: Option<RandomCrashes>,
+    crash_rng: SimRng,
+    /// For subscribers with a planned random crash, the time they will be restarted by
+    planned_crashes: HashMap<String, SimTime>,
+    /// Explicitly set subscriber clocks
+    clocks: HashMap<String, Clock>,
+    random_clocks: Option<RandomClocks>,
+    /// Random clocks drawn so far, by destination
+    drawn_clocks: RefCell<HashMap<String, Clock>>,
+    /// Global time the simulation started at, where clock offsets and drift are measured from
+    origin: SimTime,
+    /// Tick interval for event-driven stepping
+    tick_interval: Option<std::time::Duration>,
+    next_tick: SimTime,
+    time: SimTime,
+    seed: u64,
+    unknown_destination: UnknownDestination,
+    /// Set while replaying a trace, see [Simulator::start_replay]
+    replay: Option<Replay>,
+    invariants: Vec<Invariant>,
+    /// The user hook, and a recorder of the last events while there are invariants to check
+    hook: (H, Option<Recorder>),
+}
+
+/// How many of the last events are shown when an invariant fails.
+const RECENT_EVENTS: usize = 32;
+
+/// The state of a trace being replayed.
+struct Replay {
+    events: VecDeque<TraceEvent>,
+    /// Published envelopes waiting for their recorded delivery
+    pending: HashMap<u64, Envelope>,
+    /// How many more times each envelope is delivered (duplicated envelopes are delivered more
+    /// than once)
+    deliveries: HashMap<u64, usize>,
+    /// Why each dropped envelope was dropped, to replay dead letters
+    dropped: HashMap<u64, DropReason>,
+}
+
+impl Replay {
+    /// Takes a pending envelope for delivery, keeping a copy if it is delivered again later.
+    fn take(&mut self, id: u64) -> Option<Envelope> {
+        let remaining = self.deliveries.get_mut(&id)?;
+        *remaining = remaining.saturating_sub(1);
+        if *remaining == 0 {
+            return self.pending.remove(&id);
+        }
+        let envelope = self.pending.get(&id)?;
+        Some(Envelope {
+            message: envelope.message.try_clone()?,
+            priority: envelope.priority,
+            destination: envelope.destination.clone(),
+            source: envelope.source.clone(),
+            delay: envelope.delay,
+            id,
+        })
+    }
 }
 
 impl Simulator<NoOpHook> {
@@ -20,9 +88,12 @@ impl Simulator<NoOpHook> {
     /// The number of queues is determined by the length of the initial_events vector,
     /// and this must match the number of queues in a [crate::message_bus::MessageBus] to accurately simulate
     /// the message bus.
+    ///
+    /// Subscribers are visited in [SubscriberOrder::Sorted] order unless changed with
+    /// [Simulator::with_subscriber_order].
     pub fn new(
-        subscribers: HashMap<String, Box<dyn Subscriber>>,
-        initial_time: std::time::SystemTime,
+        subscribers: impl IntoIterator<Item = (String, Box<dyn Subscriber>)>,
+        initial_time: SimTime,
         initial_events: Vec<Vec<SimulatorEvent>>,
     ) -> Self {
         Self::with_hook(subscribers, initial_time, initial_events, NoOpHook)
@@ -36,8 +107,8 @@ impl<H: PublishHook> Simulator<H> {
     /// and this must match the number of queues in a [crate::message_bus::MessageBus] to accurately simulate
     /// the message bus.
     pub fn with_hook(
-        subscribers: HashMap<String, Box<dyn Subscriber>>,
-        initial_time: std::time::SystemTime,
+        subscribers: impl IntoIterator<Item = (String, Box<dyn Subscriber>)>,
+        initial_time: SimTime,
         initial_events: Vec<Vec<SimulatorEvent>>,
         hook: H,
     ) -> Self {
@@ -49,83 +120,865 @@ impl<H: PublishHook> Simulator<H> {
         if events.is_empty() {
             events.push(VecDeque::new());
         }
+        // Initial envelopes are published from outside
+        let mut network = Network::new(0);
+        for event in events.iter_mut().flatten() {
+            if let SimulatorEvent::Envelope(envelope, at) = event {
+                let source = std::mem::take(&mut envelope.source);
+                network.stamp(&source, envelope);
+                hook.on_publish(envelope, *at);
+            }
+        }
         Self {
-            subscribers,
+            subscribers: subscribers.into_iter().collect(),
             events,
+            network,
+            timers: Timers::default(),
+            scheduled: VecDeque::new(),
+            factories: HashMap::new(),
+            crashes: None,
+            crash_rng: SimRng::derive(0, "dsim::crashes"),
+            planned_crashes: HashMap::new(),
+            clocks: HashMap::new(),
+            random_clocks: None,
+            drawn_clocks: RefCell::new(HashMap::new()),
+            origin: initial_time,
+            tick_interval: None,
+            next_tick: initial_time,
             time: initial_time,
-            hook,
+            seed: 0,
+            unknown_destination: UnknownDestination::Panic,
+            replay: None,
+            invariants: Vec::new(),
+            hook: (hook, None),
+        }
+    }
+
+    /// Sets the seed for the simulation, defaults to 0.
+    ///
+    /// Each subscriber gets its own [crate::message_bus::SimRng] through
+    /// [crate::message_bus::Context::rng], derived from this seed and the subscriber's
+    /// destination name, so the whole run reproduces exactly from one seed.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.subscribers.set_seed(seed);
+        self.network.set_seed(seed);
+        self.crash_rng = SimRng::derive(seed, "dsim::crashes");
+        self.drawn_clocks.get_mut().clear();
+        self
+    }
+
+    /// Sets the model deciding how long each envelope takes to be delivered, see
+    /// [crate::message_bus::FixedLatency], [crate::message_bus::UniformLatency],
+    /// [crate::message_bus::ExponentialLatency] and [crate::message_bus::PerLinkLatency].
+    ///
+    /// Envelopes are held until their delivery time, and are then delivered on the first step
+    /// that starts after it. Without a latency model every envelope is delivered on the next step.
+    pub fn with_latency(mut self, latency: impl LatencyModel) -> Self {
+        self.network.set_latency(Box::new(latency));
+        self
+    }
+
+    /// The seed this simulation was created with.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The current virtual time.
+    pub fn time(&self) -> SimTime {
+        self.time
+    }
+
+    /// Injects drops, duplicates and reordering into the network, see [NetworkFaults].
+    ///
+    /// Dropped envelopes are reported to [PublishHook::on_drop].
+    pub fn with_faults(mut self, faults: NetworkFaults) -> Self {
+        self.network.set_faults(faults);
+        self
+    }
+
+    /// Randomly crashes and restarts subscribers, see [RandomCrashes].
+    pub fn with_random_crashes(mut self, crashes: RandomCrashes) -> Self {
+        self.crashes = Some(crashes);
+        self
+    }
+
+    /// Gives a subscriber its own skewed [Clock]. The `at` passed to its calls, and the timers it
+    /// sets, are then in its local time, while the simulator keeps running on global time.
+    pub fn with_clock(mut self, destination: &str, clock: Clock) -> Self {
+        self.set_clock(destination, clock);
+        self
+    }
+
+    /// Gives every subscriber without an explicit [Clock] a random one, see [RandomClocks].
+    ///
+    /// Panics if the simulation started less than `max_offset` after [SimTime::EPOCH].
+    pub fn with_random_clocks(mut self, clocks: RandomClocks) -> Self {
+        assert!(
+            self.origin.since_epoch() >= clocks.max_offset,
+            "random clocks up to {:?} behind would read before SimTime::EPOCH, start the simulation later",
+            clocks.max_offset
+        );
+        self.random_clocks = Some(clocks);
+        self.drawn_clocks.get_mut().clear();
+        self
+    }
+
+    /// Changes a subscriber's [Clock] between steps. Pending timers keep their global due time.
+    ///
+    /// Panics if the clock would read before [SimTime::EPOCH], see [Clock::behind].
+    pub fn set_clock(&mut self, destination: &str, clock: Clock) {
+        assert!(
+            clock.fits(self.origin),
+            "the clock of {destination} would read before SimTime::EPOCH, start the simulation later"
+        );
+        self.clocks.insert(destination.to_string(), clock);
+    }
+
+    /// The clock of a subscriber: explicitly set, random, or the global clock.
+    fn clock(&self, destination: &str) -> Clock {
+        if let Some(clock) = self.clocks.get(destination) {
+            return *clock;
+        }
+        let Some(clocks) = self.random_clocks else {
+            return Clock::default();
+        };
+        *self
+            .drawn_clocks
+            .borrow_mut()
+            .entry(destination.to_string())
+            .or_insert_with(|| {
+                let mut rng = SimRng::derive(self.seed, &format!("dsim::clock::{destination}"));
+                Clock::random(clocks, &mut rng)
+            })
+    }
+
+    /// The current time on a subscriber's local clock.
+    pub fn local_time(&self, destination: &str) -> SimTime {
+        self.clock(destination).local(self.origin, self.time)
+    }
+
+    /// Converts a global time to a subscriber's local time.
+    fn local(&self, destination: &str, at: SimTime) -> SimTime {
+        self.clock(destination).local(self.origin, at)
+    }
+
+    /// Sets what happens to envelopes sent to a destination without a subscriber, see
+    /// [UnknownDestination]. Defaults to [UnknownDestination::Panic], as an unknown destination in
+    /// a simulation is almost always a typo (unlike the [crate::message_bus::MessageBus], which
+    /// drops them).
+    pub fn with_unknown_destination(mut self, policy: UnknownDestination) -> Self {
+        self.unknown_destination = policy;
+        self
+    }
+
+    /// Sets what happens to envelopes that will never be delivered, see [DeadLetters]. Dead
+    /// letters for a [DeadLetters::Destination] are delivered on the next step.
+    pub fn with_dead_letters(mut self, dead_letters: DeadLetters) -> Self {
+        self.network.set_dead_letters(dead_letters);
+        self
+    }
+
+    /// Records every fault injected from now on: dropped, duplicated and delayed envelopes, and
+    /// applied scheduled actions (including random crashes and restarts). Read them with
+    /// [Simulator::fault_schedule].
+    pub fn with_fault_recording(mut self) -> Self {
+        self.network.record_faults();
+        self
+    }
+
+    /// Injects exactly the faults of `schedule` (typically recorded with
+    /// [Simulator::fault_schedule]) instead of drawing them from the seed: network faults and
+    /// random crashes are turned off, and the scheduled actions are replaced by the actions in the
+    /// schedule.
+    pub fn with_fault_schedule(mut self, schedule: FaultSchedule) -> Self {
+        self.crashes = None;
+        self.scheduled.clear();
+        self.network.set_script(&schedule.faults);
+        for fault in schedule.faults {
+            if let Fault::Action { action, at } = fault {
+                self.schedule(at, action);
+            }
+        }
+        self
+    }
+
+    /// The faults injected so far, if recording them (see [Simulator::with_fault_recording]).
+    /// Empty otherwise.
+    pub fn fault_schedule(&self) -> FaultSchedule {
+        FaultSchedule {
+            faults: self.network.faults().to_vec(),
+        }
+    }
+
+    /// Registers an invariant: a property that must hold across all subscribers, checked through
+    /// a read-only [SubscriberView] after every step ([Simulator::step], [Simulator::step_next]
+    /// and [Simulator::replay_next]).
+    ///
+    /// The check returns an error describing the violation, and the step then panics with the
+    /// invariant name, the error, the virtual time, the seed, and the last events of the run.
+    pub fn with_invariant(
+        mut self,
+        name: &str,
+        check: impl FnMut(&SubscriberView) -> Result<(), String> + Send + 'static,
+    ) -> Self {
+        self.invariants.push(Invariant {
+            name: name.to_string(),
+            check: Box::new(check),
+        });
+        self.hook.1.get_or_insert_with(|| Recorder::last(RECENT_EVENTS));
+        self
+    }
+
+    /// Checks every invariant, panicking on the first violation.
+    fn check_invariants(&mut self) {
+        let view = SubscriberView::new(&self.subscribers, self.time);
+        for invariant in self.invariants.iter_mut() {
+            if let Err(error) = (invariant.check)(&view) {
+                let recent = self.hook.1.as_ref().map(Recorder::take).unwrap_or_default();
+                let events: Vec<String> = recent
+                    .events
+                    .iter()
+                    .map(|event| format!("  {:?}", event))
+                    .collect();
+                panic!(
+                    "invariant {:?} violated at {} (seed {}): {}\nlast {} events:\n{}",
+                    invariant.name,
+                    self.time,
+                    self.seed,
+                    error,
+                    events.len(),
+                    events.join("\n")
+                );
+            }
+        }
+    }
+
+    /// Sets the order in which subscribers are visited whenever the simulator iterates all of
+    /// them, such as when running ticks.
+    pub fn with_subscriber_order(mut self, order: SubscriberOrder) -> Self {
+        self.subscribers.set_order(order);
+        self
+    }
+
+    /// Adds a subscriber between steps, replacing any existing subscriber with the same
+    /// destination (even a crashed one, the new subscriber starts out running with a fresh rng,
+    /// and the timers of the old one are cancelled). It is ticked from the next step on.
+    ///
+    /// Subscribers can also add subscribers while running with
+    /// [crate::message_bus::Context::spawn].
+    pub fn subscribe(&mut self, destination: String, subscriber: Box<dyn Subscriber>) {
+        self.insert_subscriber(destination, subscriber);
+    }
+
+    /// Inserts a subscriber, cancelling the timers of the one it replaces.
+    fn insert_subscriber(&mut self, destination: String, subscriber: Box<dyn Subscriber>) {
+        if self.subscribers.insert(destination.clone(), subscriber).is_some() {
+            self.timers.extract(|(name, _, _)| *name == destination);
+        }
+    }
+
+    /// Removes a subscriber between steps, returning it if it existed. Every envelope queued or in
+    /// flight to it is dropped, and its timers and scheduled crashes and restarts are cancelled,
+    /// so they don't carry over to a later subscriber with the same destination.
+    pub fn unsubscribe(&mut self, destination: &str) -> Option<Box<dyn Subscriber>> {
+        let subscriber = self.subscribers.remove(destination)?;
+        self.factories.remove(destination);
+        self.planned_crashes.remove(destination);
+        self.timers.extract(|(name, _, _)| name == destination);
+        self.scheduled.retain(|(_, action)| {
+            !matches!(action, SimAction::Crash(name) | SimAction::Restart(name) if name == destination)
+        });
+        self.drop_inbound(destination, DropReason::Unsubscribed);
+        Some(subscriber)
+    }
+
+    /// Applies an action immediately.
+    pub fn apply(&mut self, action: SimAction) {
+        match action {
+            SimAction::Crash(destination) => self.crash(&destination),
+            SimAction::Restart(destination) => self.restart(&destination),
+            action => self.network.apply(&action),
+        }
+    }
+
+    /// Applies an action at the start of the first step at or after `at`.
+    pub fn schedule(&mut self, at: SimTime, action: SimAction) {
+        let i = self.scheduled.partition_point(|(scheduled_at, _)| *scheduled_at <= at);
+        self.scheduled.insert(i, (at, action));
+    }
+
+    /// Schedules the actions of a [Scenario], at their offset from the initial time of the
+    /// simulation. Scenarios combine with network faults and random crashes.
+    pub fn with_scenario(mut self, scenario: Scenario) -> Self {
+        for (offset, action) in scenario.actions {
+            self.schedule(self.origin + offset, action);
+        }
+        self
+    }
+
+    /// Splits destinations into groups that can only reach each other within the group,
+    /// see [SimAction::Partition].
+    ///
+    /// Reachability is checked both when an envelope is published and when it is delivered, so
+    /// envelopes already in flight across the new partition are dropped too.
+    pub fn partition(&mut self, groups: &[&[&str]]) {
+        let groups = groups
+            .iter()
+            .map(|group| group.iter().map(|destination| destination.to_string()).collect())
+            .collect();
+        self.apply(SimAction::Partition(groups));
+    }
+
+    /// Removes any partition and restores every cut link.
+    pub fn heal(&mut self) {
+        self.apply(SimAction::Heal);
+    }
+
+    /// Stops delivering envelopes from `source` to `destination`.
+    pub fn cut_link(&mut self, source: &str, destination: &str) {
+        self.apply(SimAction::CutLink {
+            source: source.to_string(),
+            destination: destination.to_string(),
+        });
+    }
+
+    /// Resumes delivering envelopes from `source` to `destination`.
+    pub fn restore_link(&mut self, source: &str, destination: &str) {
+        self.apply(SimAction::RestoreLink {
+            source: source.to_string(),
+            destination: destination.to_string(),
+        });
+    }
+
+    /// Crashes a subscriber: it stops being ticked, [Subscriber::on_crash] is called, its timers
+    /// are cancelled, and every envelope in flight to it is dropped. Envelopes sent to it while
+    /// crashed are dropped too.
+    ///
+    /// Does nothing if the subscriber is unknown or already crashed.
+    pub fn crash(&mut self, destination: &str) {
+        let Some(entry) = self.subscribers.get_mut(destination) else {
+            return;
+        };
+        if entry.crashed {
+            return;
+        }
+        entry.crashed = true;
+        entry.subscriber.on_crash();
+        self.hook.on_crash(destination, self.time);
+        self.timers.extract(|(name, _, _)| name == destination);
+        self.drop_inbound(destination, DropReason::Crashed);
+    }
+
+    /// Drops every envelope queued or in flight to `destination`.
+    fn drop_inbound(&mut self, destination: &str, reason: DropReason) {
+        let at = self.time;
+        let mut dropped = vec![];
+        for queue in self.events.iter_mut() {
+            let (inbound, kept): (VecDeque<_>, VecDeque<_>) =
+                std::mem::take(queue).into_iter().partition(|event| {
+                    matches!(event, SimulatorEvent::Envelope(envelope, _) if envelope.destination == destination)
+                });
+            *queue = kept;
+            dropped.extend(inbound);
+        }
+        for event in dropped {
+            if let SimulatorEvent::Envelope(envelope, _) = event {
+                self.network
+                    .drop_envelope(&self.hook, envelope, reason, at, &mut self.events);
+            }
+        }
+        self.network
+            .drop_inbound(&self.hook, destination, reason, at, &mut self.events);
+    }
+
+    /// Restarts a crashed subscriber, using a fresh instance from its restart factory if one
+    /// was registered with [Simulator::set_restart_factory], otherwise resuming the crashed
+    /// instance with whatever state it kept.
+    ///
+    /// Does nothing if the subscriber is unknown or not crashed.
+    pub fn restart(&mut self, destination: &str) {
+        let Some(entry) = self.subscribers.get_mut(destination) else {
+            return;
+        };
+        if !entry.crashed {
+            return;
+        }
+        if let Some(factory) = self.factories.get_mut(destination) {
+            entry.subscriber = factory();
+        }
+        entry.crashed = false;
+        self.hook.on_restart(destination, self.time);
+    }
+
+    /// Restarts a crashed subscriber with the given instance.
+    ///
+    /// Does nothing if the subscriber is unknown or not crashed.
+    pub fn restart_with(&mut self, destination: &str, subscriber: Box<dyn Subscriber>) {
+        let Some(entry) = self.subscribers.get_mut(destination) else {
+            return;
+        };
+        if !entry.crashed {
+            return;
+        }
+        entry.subscriber = subscriber;
+        entry.crashed = false;
+        self.hook.on_restart(destination, self.time);
+    }
+
+    /// Registers a factory that creates the instance a subscriber restarts with, for restarts
+    /// that don't provide one (scheduled and random restarts).
+    pub fn set_restart_factory(
+        &mut self,
+        destination: &str,
+        factory: impl FnMut() -> Box<dyn Subscriber> + Send + 'static,
+    ) {
+        self.factories.insert(destination.to_string(), Box::new(factory));
+    }
+
+    /// Whether the subscriber is currently crashed.
+    pub fn is_crashed(&self, destination: &str) -> bool {
+        self.subscribers
+            .get(destination)
+            .is_some_and(|entry| entry.crashed)
+    }
+
+    /// The subscriber registered under `destination`, if there is one and it is a `T`.
+    ///
+    /// Crashed subscribers are returned as they were left by [Subscriber::on_crash].
+    pub fn subscriber<T: Subscriber>(&self, destination: &str) -> Option<&T> {
+        self.subscribers
+            .get(destination)?
+            .subscriber
+            .downcast_ref::<T>()
+    }
+
+    /// The subscriber registered under `destination`, if there is one and it is a `T`.
+    pub fn subscriber_mut<T: Subscriber>(&mut self, destination: &str) -> Option<&mut T> {
+        self.subscribers
+            .get_mut(destination)?
+            .subscriber
+            .downcast_mut::<T>()
+    }
+
+    /// Ends the simulation, returning every subscriber by destination. Use
+    /// `downcast::<T>()` to get them back as their own type.
+    pub fn into_subscribers(self) -> HashMap<String, Box<dyn Subscriber>> {
+        self.subscribers
+            .into_entries()
+            .map(|entry| (entry.name, entry.subscriber))
+            .collect()
+    }
+
+    /// Schedules the next random crash and restart of every running subscriber that doesn't
+    /// have one planned yet.
+    fn plan_random_crashes(&mut self) {
+        let Some(crashes) = self.crashes else {
+            return;
+        };
+        let now = self.time;
+        let mut planned = vec![];
+        for entry in self.subscribers.running_mut() {
+            if self
+                .planned_crashes
+                .get(&entry.name)
+                .is_some_and(|restart_at| *restart_at > now)
+            {
+                continue;
+            }
+            // Exponentially distributed uptime, 1 - u is in (0, 1] so ln never sees 0
+            let u = 1.0 - self.crash_rng.next_f64();
+            let uptime = -u.ln() * crashes.mean_time_between_crashes.as_secs_f64();
+            let crash_at = now + std::time::Duration::from_secs_f64(uptime.min(u32::MAX as f64));
+            let restart_at = crash_at
+                + self
+                    .crash_rng
+                    .gen_duration(crashes.min_downtime, crashes.max_downtime);
+            planned.push((entry.name.clone(), crash_at, restart_at));
+        }
+        for (destination, crash_at, restart_at) in planned {
+            self.schedule(crash_at, SimAction::Crash(destination.clone()));
+            self.schedule(restart_at, SimAction::Restart(destination.clone()));
+            self.planned_crashes.insert(destination, restart_at);
+        }
+    }
+
+    /// Applies every scheduled action that is due at the current time.
+    fn apply_scheduled(&mut self) {
+        while self.scheduled.front().is_some_and(|(at, _)| *at <= self.time) {
+            let (at, action) = self.scheduled.pop_front().unwrap();
+            self.network.log_fault(Fault::Action {
+                action: action.clone(),
+                at,
+            });
+            self.apply(action);
         }
     }
 
     /// Steps the simluator by some duration, looping through all of the subscribers to
     /// run their tick, then receive for anything in the queue.
     ///
+    /// Scheduled actions that are due are applied before anything else.
+    ///
     /// Returns the new time after the step.
-    pub fn step(&mut self, step_by: std::time::Duration) -> std::time::SystemTime {
-        let subscribers = &mut self.subscribers;
+    pub fn step(&mut self, step_by: std::time::Duration) -> SimTime {
+        self.hook.on_step_start(self.time);
+        self.plan_random_crashes();
+        self.apply_scheduled();
+
         let events = std::mem::take(&mut self.events); // we are replacing this later anyway
-        let num_queues = events.len();
         let mut new_events: Vec<VecDeque<SimulatorEvent>> =
-            (0..num_queues).map(|_| VecDeque::new()).collect();
+            (0..events.len()).map(|_| VecDeque::new()).collect();
 
-        // First we process all of the ticks
-        for subscriber in subscribers.values_mut() {
-            let envelopes = subscriber.tick(self.time);
-            for envelope in envelopes {
-                self.hook.on_publish(&envelope, self.time);
-                let priority = envelope.priority.min(num_queues - 1);
-                new_events[priority].push_back(SimulatorEvent::Envelope(envelope, self.time));
-            }
-        }
+        // First we process all of the ticks, and any timers that are due
+        self.tick_all(self.time, &mut new_events);
+        self.fire_timers(&mut new_events);
 
         // Then we increment the time to simulate the passing of time
         self.time += step_by;
 
-        // Then we process all of the events in the queue, in decreasing priority order (highest first)
+        // Then we process all of the events in the queue
+        self.process(events, &mut new_events);
+
+        // Anything that arrived by now is delivered on the next step
+        self.network.deliver_due(self.time, &mut new_events);
+        // Reset the events queue
+        self.events = new_events;
+        self.hook.on_step_end(self.time);
+        self.check_invariants();
+        self.time
+    }
+
+    /// Sets the interval of subscriber ticks for event-driven stepping ([Simulator::step_next]
+    /// and [Simulator::run_until]), starting with a tick at the current time.
+    ///
+    /// Without a tick interval, subscribers are never ticked in event-driven mode and only react
+    /// to envelopes.
+    ///
+    /// Panics if the interval is zero, time would never move past the first tick.
+    pub fn with_tick_interval(mut self, interval: std::time::Duration) -> Self {
+        assert!(!interval.is_zero(), "the tick interval must not be zero");
+        self.tick_interval = Some(interval);
+        self.next_tick = self.time;
+        self
+    }
+
+    /// The virtual time of the next pending event (queued or in flight envelope, timer, scheduled
+    /// action, or tick), if there is one.
+    pub fn next_event_time(&self) -> Option<SimTime> {
+        let queued = self
+            .events
+            .iter()
+            .any(|queue| !queue.is_empty())
+            .then_some(self.time);
+        let tick = self.tick_interval.map(|_| self.next_tick);
+        [
+            queued,
+            self.network.next_delivery(),
+            self.timers.next(),
+            self.scheduled.front().map(|(at, _)| *at),
+            tick,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Event-driven stepping: advances virtual time directly to the next pending event and
+    /// processes everything due at that instant (scheduled actions, then ticks, then timers, then
+    /// deliveries).
+    ///
+    /// Envelopes emitted without latency are delivered by the next call, at the same virtual
+    /// time. Returns the new time, or `None` (without changing the time) if nothing is pending.
+    pub fn step_next(&mut self) -> Option<SimTime> {
+        self.plan_random_crashes();
+        let at = self.next_event_time()?;
+        self.time = self.time.max(at);
+        self.hook.on_step_start(self.time);
+        self.apply_scheduled();
+
+        let mut events = std::mem::take(&mut self.events);
+        let mut new_events: Vec<VecDeque<SimulatorEvent>> =
+            (0..events.len()).map(|_| VecDeque::new()).collect();
+        self.network.deliver_due(self.time, &mut events);
+
+        if let Some(interval) = self.tick_interval
+            && self.next_tick <= self.time
+        {
+            self.tick_all(self.time, &mut new_events);
+            while self.next_tick <= self.time {
+                self.next_tick += interval;
+            }
+        }
+        self.fire_timers(&mut new_events);
+
+        self.process(events, &mut new_events);
+        self.events = new_events;
+        self.hook.on_step_end(self.time);
+        self.check_invariants();
+        Some(self.time)
+    }
+
+    /// Event-driven stepping: processes every event up to and including `time`, jumping
+    /// straight from one event to the next, then sets the time to `time`.
+    ///
+    /// Returns the new time.
+    pub fn run_until(&mut self, time: SimTime) -> SimTime {
+        loop {
+            self.plan_random_crashes();
+            match self.next_event_time() {
+                Some(next) if next <= time => {
+                    self.step_next();
+                }
+                _ => break,
+            }
+        }
+        self.time = self.time.max(time);
+        self.time
+    }
+
+    /// Starts replaying a [Trace] recorded by a [crate::message_bus::Recorder], typically from
+    /// a [crate::message_bus::MessageBus] run, to reproduce it exactly.
+    ///
+    /// Each [Simulator::replay_next] then replays one event: ticks, timers and deliveries happen
+    /// at their recorded times and in their recorded order, instead of following the simulated
+    /// network, and envelopes published from outside are published again from the trace. The
+    /// simulator must have the same subscribers (in their initial state) and seed as the recorded
+    /// run.
+    pub fn start_replay(&mut self, trace: Trace) {
+        let mut deliveries = HashMap::new();
+        for event in trace.events.iter() {
+            if let TraceEvent::Deliver { id, .. } = event {
+                *deliveries.entry(*id).or_insert(0) += 1;
+            }
+        }
+        self.replay = Some(Replay {
+            events: trace.events.into(),
+            pending: HashMap::new(),
+            deliveries,
+            dropped: HashMap::new(),
+        });
+    }
+
+    /// Replays the next event of the trace passed to [Simulator::start_replay].
+    ///
+    /// Returns the new time, or `None` once the trace is exhausted.
+    ///
+    /// # Panics
+    ///
+    /// If the run diverges from the trace, i.e. a subscriber did not emit an envelope the trace
+    /// says it did, or if an envelope published from outside can't be replayed because its
+    /// message does not implement [crate::message_bus::Message::try_clone].
+    pub fn replay_next(&mut self) -> Option<SimTime> {
+        let Some(event) = self.replay.as_mut()?.events.pop_front() else {
+            self.replay = None;
+            return None;
+        };
+        self.time = self.time.max(event.at());
+        self.hook.on_step_start(self.time);
+        self.replay_event(event);
+        self.hook.on_step_end(self.time);
+        self.check_invariants();
+        Some(self.time)
+    }
+
+    /// Replays one event of the trace.
+    fn replay_event(&mut self, event: TraceEvent) {
+        match event {
+            TraceEvent::Tick { at } => self.tick_all(at, &mut []),
+            TraceEvent::Timer { destination, token, at } => {
+                let Some(subscriber) = self.subscribers.get_mut(&destination) else {
+                    return;
+                };
+                self.hook.on_timer(&destination, token, at);
+                let outcome = subscriber.timer(token, at);
+                self.handle(&destination, outcome, at, &mut []);
+            }
+            TraceEvent::Publish {
+                id,
+                source,
+                destination,
+                priority,
+                delay,
+                at,
+                message,
+                ..
+            } => {
+                let replay = self.replay.as_mut().unwrap();
+                if source.is_empty() {
+                    let message = message.unwrap_or_else(|| {
+                        panic!("envelope {id} to {destination} was published from outside, but its message can't be cloned to replay it")
+                    });
+                    let envelope = Envelope {
+                        message,
+                        priority,
+                        destination,
+                        source,
+                        delay,
+                        id,
+                    };
+                    self.network.skip_ids_to(id);
+                    self.hook.on_publish(&envelope, at);
+                    replay.pending.insert(id, envelope);
+                } else if replay
+                    .pending
+                    .get(&id)
+                    .is_none_or(|envelope| envelope.destination != destination)
+                {
+                    panic!("replay diverged at {at}: {source} did not publish envelope {id} to {destination}");
+                }
+            }
+            TraceEvent::Deliver { id, destination, at } => {
+                let replay = self.replay.as_mut().unwrap();
+                let Some(mut envelope) = replay.take(id) else {
+                    return;
+                };
+                // Delivered somewhere else after being dropped: a dead letter
+                if envelope.destination != destination
+                    && let Some(reason) = replay.dropped.get(&id)
+                {
+                    envelope = DeadLetter::wrap(envelope, &destination, *reason);
+                }
+                let Some(subscriber) = self.subscribers.get_mut(&destination) else {
+                    return;
+                };
+                self.hook.on_deliver(&envelope, at);
+                let outcome = subscriber.receive(envelope, at);
+                self.handle(&destination, outcome, at, &mut []);
+            }
+            TraceEvent::Crash { destination, .. } => self.crash(&destination),
+            TraceEvent::Restart { destination, .. } => self.restart(&destination),
+            TraceEvent::Drop { id, reason, at, .. } => {
+                // Only forget the envelope once it won't be delivered anymore
+                let replay = self.replay.as_mut().unwrap();
+                replay.dropped.insert(id, reason);
+                if replay.deliveries.get(&id).is_some_and(|remaining| *remaining > 0) {
+                    if let Some(envelope) = replay.pending.get(&id) {
+                        self.hook.on_drop(envelope, reason, at);
+                    }
+                } else if let Some(envelope) = replay.pending.remove(&id) {
+                    self.hook.on_drop(&envelope, reason, at);
+                }
+            }
+        }
+    }
+
+    /// Replays a whole [Trace], see [Simulator::start_replay].
+    ///
+    /// Returns the new time.
+    pub fn replay(&mut self, trace: Trace) -> SimTime {
+        self.start_replay(trace);
+        while self.replay_next().is_some() {}
+        self.time
+    }
+
+    /// Ticks every running subscriber at `at`.
+    fn tick_all(&mut self, at: SimTime, new_events: &mut [VecDeque<SimulatorEvent>]) {
+        self.hook.on_tick(at);
+        for name in self.subscribers.running_names() {
+            let local = self.local(&name, at);
+            let outcome = self.subscribers.get_mut(&name).unwrap().tick(local);
+            self.handle(&name, outcome, at, new_events);
+        }
+    }
+
+    /// Fires every timer that is due at the current time. Timers set while firing are due on
+    /// the next step at the earliest.
+    fn fire_timers(&mut self, new_events: &mut [VecDeque<SimulatorEvent>]) {
+        for (at, (name, token, local)) in self.timers.take_due(self.time) {
+            let Some(subscriber) = self.subscribers.get_mut(&name) else {
+                continue;
+            };
+            self.hook.on_timer(&name, token, at);
+            let outcome = subscriber.timer(token, local);
+            self.handle(&name, outcome, at, new_events);
+        }
+    }
+
+    /// Applies everything a subscriber produced during one call at global time `at`.
+    fn handle(
+        &mut self,
+        source: &str,
+        outcome: Outcome,
+        at: SimTime,
+        new_events: &mut [VecDeque<SimulatorEvent>],
+    ) {
+        if let Some(replay) = &mut self.replay {
+            // Deliveries and timers follow the trace instead
+            for mut envelope in outcome.envelopes {
+                self.network.stamp(source, &mut envelope);
+                self.hook.on_publish(&envelope, at);
+                replay.pending.insert(envelope.id, envelope);
+            }
+            for (destination, subscriber) in outcome.spawned {
+                self.insert_subscriber(destination, subscriber);
+            }
+            return;
+        }
+        self.network
+            .publish(&self.hook, source, outcome.envelopes, at, new_events);
+        let clock = self.clock(source);
+        for (timer_at, token) in outcome.timers {
+            let global = clock.global(self.origin, timer_at);
+            self.timers.push(global, (source.to_string(), token, timer_at));
+        }
+        for (destination, subscriber) in outcome.spawned {
+            self.insert_subscriber(destination, subscriber);
+        }
+    }
+
+    /// Processes all of the events in the queues, in decreasing priority order (highest first).
+    fn process(&mut self, events: Vec<VecDeque<SimulatorEvent>>, new_events: &mut [VecDeque<SimulatorEvent>]) {
         for queue in events.into_iter().rev() {
             for event in queue {
                 match event {
                     SimulatorEvent::Envelope(envelope, at) => {
-                        let subscriber = subscribers.get_mut(&envelope.destination).unwrap();
-                        let envelopes = subscriber.receive(envelope.message, at);
-                        // Add any new envelopes to the appropriate priority queue
-                        for envelope in envelopes {
-                            self.hook.on_publish(&envelope, at);
-                            let priority = envelope.priority.min(num_queues - 1);
-                            new_events[priority].push_back(SimulatorEvent::Envelope(envelope, at));
+                        let Some(envelope) = self.unknown_destination.route(
+                            &self.subscribers,
+                            self.network.dead_letters(),
+                            &self.hook,
+                            envelope,
+                            at,
+                        ) else {
+                            continue;
+                        };
+                        // The topology may have changed while the envelope was in flight. Dead
+                        // letters are handed over locally, not over the network.
+                        if !self.network.reachable(&envelope.source, &envelope.destination)
+                            && envelope.message.downcast_ref::<DeadLetter>().is_none()
+                        {
+                            self.network
+                                .drop_envelope(&self.hook, envelope, DropReason::Partitioned, at, new_events);
+                            continue;
                         }
-                    }
-                    SimulatorEvent::Tick(at) => {
-                        for subscriber in subscribers.values_mut() {
-                            let envelopes = subscriber.tick(at);
-                            // Add any new envelopes to the appropriate priority queue
-                            for envelope in envelopes {
-                                self.hook.on_publish(&envelope, at);
-                                let priority = envelope.priority.min(num_queues - 1);
-                                new_events[priority]
-                                    .push_back(SimulatorEvent::Envelope(envelope, at));
-                            }
+                        let local = self.local(&envelope.destination, at);
+                        let subscriber = self.subscribers.get_mut(&envelope.destination).unwrap();
+                        if subscriber.crashed {
+                            self.network
+                                .drop_envelope(&self.hook, envelope, DropReason::Crashed, at, new_events);
+                            continue;
                         }
+                        self.hook.on_deliver(&envelope, at);
+                        let outcome = subscriber.receive(envelope, local);
+                        let name = subscriber.name.clone();
+                        // Add any new envelopes to the appropriate priority queue
+                        self.handle(&name, outcome, at, new_events);
                     }
+                    SimulatorEvent::Tick(at) => self.tick_all(at, new_events),
                 }
             }
         }
-        // Reset the events queue
-        self.events = new_events;
-        self.time
     }
 
     pub fn step_to(
         &mut self,
-        time: std::time::SystemTime,
+        time: SimTime,
         step_by: std::time::Duration,
-    ) -> std::time::SystemTime {
+    ) -> SimTime {
         // Keep stepping until we reach the target time
         while self.time < time {
             // Calculate the remaining time to reach the target
-            let remaining_time = time
-                .duration_since(self.time)
-                .unwrap_or(std::time::Duration::ZERO);
+            let remaining_time = time.duration_since(self.time);
 
             // Step by the minimum of step_by and remaining time
             let actual_step = step_by.min(remaining_time);
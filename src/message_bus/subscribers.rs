@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use crate::message_bus::{Context, Envelope, Outcome, SimRng, SimTime, Subscriber};
+
+/// The order in which the [crate::message_bus::Simulator] and [crate::message_bus::MessageBus]
+/// visit subscribers whenever they iterate all of them (e.g. to run ticks).
+///
+/// Both orders are independent of hashing, so two runs with the same inputs always visit
+/// subscribers in the same order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubscriberOrder {
+    /// Visit subscribers sorted by their destination name. This is the default, as it does not
+    /// depend on how the subscribers were collected before being handed over.
+    #[default]
+    Sorted,
+    /// Visit subscribers in the order they were added.
+    ///
+    /// Note that if the subscribers are passed in from a [HashMap], the insertion order is the
+    /// (random) iteration order of that map.
+    Insertion,
+}
+
+/// A registered subscriber along with the per-subscriber state the runtime keeps for it.
+pub(crate) struct Entry {
+    pub(crate) name: String,
+    pub(crate) subscriber: Box<dyn Subscriber>,
+    pub(crate) rng: SimRng,
+    /// Crashed subscribers are neither ticked nor delivered to
+    pub(crate) crashed: bool,
+}
+
+impl Entry {
+    pub(crate) fn tick(&mut self, at: SimTime) -> Outcome {
+        let mut ctx = Context::new(&self.name, &mut self.rng);
+        let envelopes = self.subscriber.tick(&mut ctx, at);
+        ctx.finish(envelopes)
+    }
+
+    pub(crate) fn timer(&mut self, token: u64, at: SimTime) -> Outcome {
+        let mut ctx = Context::new(&self.name, &mut self.rng);
+        let envelopes = self.subscriber.timer(&mut ctx, token, at);
+        ctx.finish(envelopes)
+    }
+
+    pub(crate) fn receive(&mut self, envelope: Envelope, at: SimTime) -> Outcome {
+        let mut ctx = Context::new(&self.name, &mut self.rng);
+        let envelopes = self
+            .subscriber
+            .receive(&mut ctx, envelope.message, &envelope.source, at);
+        ctx.finish(envelopes)
+    }
+}
+
+/// A named set of subscribers with a deterministic iteration order.
+pub(crate) struct Subscribers {
+    order: SubscriberOrder,
+    seed: u64,
+    /// Entries in insertion order
+    entries: Vec<Entry>,
+    /// Destination name to index in `entries`
+    index: HashMap<String, usize>,
+    /// Indices into `entries` in visiting order
+    visit: Vec<usize>,
+}
+
+impl Subscribers {
+    pub(crate) fn new(order: SubscriberOrder) -> Self {
+        Self {
+            order,
+            seed: 0,
+            entries: Vec::new(),
+            index: HashMap::new(),
+            visit: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_order(&mut self, order: SubscriberOrder) {
+        self.order = order;
+        self.reindex();
+    }
+
+    /// Sets the seed every subscriber's [SimRng] is derived from, resetting all of them.
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        for entry in self.entries.iter_mut() {
+            entry.rng = SimRng::derive(seed, &entry.name);
+        }
+    }
+
+    /// Inserts a subscriber, replacing (and returning) any existing subscriber with the same
+    /// destination. A replaced subscriber keeps its position in the insertion order, but the new
+    /// one starts out running with a fresh [SimRng].
+    pub(crate) fn insert(
+        &mut self,
+        destination: String,
+        subscriber: Box<dyn Subscriber>,
+    ) -> Option<Box<dyn Subscriber>> {
+        if let Some(&i) = self.index.get(&destination) {
+            let entry = &mut self.entries[i];
+            entry.rng = SimRng::derive(self.seed, &destination);
+            entry.crashed = false;
+            return Some(std::mem::replace(&mut entry.subscriber, subscriber));
+        }
+        let i = self.entries.len();
+        let position = match self.order {
+            SubscriberOrder::Sorted => {
+                let entries = &self.entries;
+                self.visit
+                    .partition_point(|&j| entries[j].name < destination)
+            }
+            SubscriberOrder::Insertion => i,
+        };
+        self.visit.insert(position, i);
+        self.index.insert(destination.clone(), i);
+        let rng = SimRng::derive(self.seed, &destination);
+        self.entries.push(Entry {
+            name: destination,
+            subscriber,
+            rng,
+            crashed: false,
+        });
+        None
+    }
+
+    /// Removes a subscriber, returning it if it existed.
+    pub(crate) fn remove(&mut self, destination: &str) -> Option<Box<dyn Subscriber>> {
+        let i = self.index.remove(destination)?;
+        let entry = self.entries.remove(i);
+        // Every entry after the removed one moved down by one
+        self.visit.retain(|&j| j != i);
+        for j in self.visit.iter_mut().chain(self.index.values_mut()) {
+            if *j > i {
+                *j -= 1;
+            }
+        }
+        Some(entry.subscriber)
+    }
+
+    pub(crate) fn get(&self, destination: &str) -> Option<&Entry> {
+        let i = *self.index.get(destination)?;
+        Some(&self.entries[i])
+    }
+
+    pub(crate) fn get_mut(&mut self, destination: &str) -> Option<&mut Entry> {
+        let i = *self.index.get(destination)?;
+        Some(&mut self.entries[i])
+    }
+
+    /// Takes every subscriber out, in insertion order.
+    pub(crate) fn into_entries(self) -> impl Iterator<Item = Entry> {
+        self.entries.into_iter()
+    }
+
+    /// Iterates the subscribers in visiting order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.visit.iter().map(|&i| &self.entries[i])
+    }
+
+    /// Iterates the subscribers in visiting order.
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut Entry> {
+        // Resolve the visiting order into mutable references without aliasing
+        let mut slots: Vec<Option<&mut Entry>> = self.entries.iter_mut().map(Some).collect();
+        self.visit
+            .iter()
+            .map(move |&i| slots[i].take().expect("visit order has no duplicates"))
+    }
+
+    /// The names of the subscribers that have not crashed, in visiting order.
+    pub(crate) fn running_names(&self) -> Vec<String> {
+        self.visit
+            .iter()
+            .map(|&i| &self.entries[i])
+            .filter(|entry| !entry.crashed)
+            .map(|entry| entry.name.clone())
+            .collect()
+    }
+
+    /// Iterates the subscribers that have not crashed, in visiting order.
+    pub(crate) fn running_mut(&mut self) -> impl Iterator<Item = &mut Entry> {
+        self.iter_mut().filter(|entry| !entry.crashed)
+    }
+
+    fn reindex(&mut self) {
+        self.index = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (entry.name.clone(), i))
+            .collect();
+        self.visit = (0..self.entries.len()).collect();
+        if self.order == SubscriberOrder::Sorted {
+            let entries = &self.entries;
+            self.visit.sort_by(|&a, &b| entries[a].name.cmp(&entries[b].name));
+        }
+    }
+}
+
+impl FromIterator<(String, Box<dyn Subscriber>)> for Subscribers {
+    fn from_iter<I: IntoIterator<Item = (String, Box<dyn Subscriber>)>>(iter: I) -> Self {
+        let mut subscribers = Self::new(SubscriberOrder::default());
+        for (destination, subscriber) in iter {
+            subscribers.insert(destination, subscriber);
+        }
+        subscribers
+    }
+}
@@ -0,0 +1,204 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A point in virtual (or, for the [crate::message_bus::MessageBus], real) time, with nanosecond
+/// resolution.
+///
+/// Unlike [SystemTime], a `SimTime` is a plain number of nanoseconds since [SimTime::EPOCH]
+/// (the unix epoch), so comparing and subtracting never fail: [SimTime::duration_since] saturates
+/// at zero instead of returning an error. The runtimes only ever move time forward, so the `at`
+/// a subscriber is passed never goes backwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct SimTime(u64);
+
+impl SimTime {
+    /// The unix epoch, the usual start of a simulation.
+    pub const EPOCH: SimTime = SimTime(0);
+    /// The latest representable time, around the year 2554.
+    pub const MAX: SimTime = SimTime(u64::MAX);
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Self(nanos)
+    }
+
+    /// Nanoseconds since [SimTime::EPOCH].
+    pub const fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// The time elapsed since [SimTime::EPOCH].
+    pub const fn since_epoch(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    /// The time elapsed from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: SimTime) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// The time elapsed from `earlier` to `self`, or `None` if `earlier` is later.
+    pub fn checked_duration_since(&self, earlier: SimTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<SimTime> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(SimTime)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<SimTime> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanos).map(SimTime)
+    }
+
+    /// `self + duration`, saturating at [SimTime::MAX].
+    pub fn saturating_add(&self, duration: Duration) -> SimTime {
+        self.checked_add(duration).unwrap_or(SimTime::MAX)
+    }
+
+    /// `self - duration`, saturating at [SimTime::EPOCH].
+    pub fn saturating_sub(&self, duration: Duration) -> SimTime {
+        self.checked_sub(duration).unwrap_or(SimTime::EPOCH)
+    }
+}
+
+/// Saturates at [SimTime::MAX].
+impl std::ops::Add<Duration> for SimTime {
+    type Output = SimTime;
+
+    fn add(self, duration: Duration) -> SimTime {
+        self.saturating_add(duration)
+    }
+}
+
+impl std::ops::AddAssign<Duration> for SimTime {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+/// Saturates at [SimTime::EPOCH].
+impl std::ops::Sub<Duration> for SimTime {
+    type Output = SimTime;
+
+    fn sub(self, duration: Duration) -> SimTime {
+        self.saturating_sub(duration)
+    }
+}
+
+impl std::ops::SubAssign<Duration> for SimTime {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+/// Same as [SimTime::duration_since], saturates at zero.
+impl std::ops::Sub<SimTime> for SimTime {
+    type Output = Duration;
+
+    fn sub(self, earlier: SimTime) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Times before the unix epoch saturate to [SimTime::EPOCH], and times after [SimTime::MAX] to
+/// [SimTime::MAX].
+impl From<SystemTime> for SimTime {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => SimTime::EPOCH + since,
+            Err(_) => SimTime::EPOCH,
+        }
+    }
+}
+
+impl From<SimTime> for SystemTime {
+    fn from(time: SimTime) -> Self {
+        UNIX_EPOCH + time.since_epoch()
+    }
+}
+
+/// Formats as seconds since the epoch, e.g. `12.500000000s`.
+impl std::fmt::Display for SimTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{:09}s", self.0 / 1_000_000_000, self.0 % 1_000_000_000)
+    }
+}
+
+/// Parses seconds since the epoch with a `ns`, `us`, `ms`, `s`, `m` or `h` unit, e.g.
+/// `12.5s` or `12500ms`. Reads back what [SimTime]'s Display writes.
+impl std::str::FromStr for SimTime {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<SimTime, String> {
+        Ok(SimTime::EPOCH + parse_duration(text.trim())?)
+    }
+}
+
+const UNITS: [(&str, u64); 6] = [
+    ("h", 3_600_000_000_000),
+    ("m", 60_000_000_000),
+    ("s", 1_000_000_000),
+    ("ms", 1_000_000),
+    ("us", 1_000),
+    ("ns", 1),
+];
+
+/// Parses a duration like `5s`, `1.5s` or `500ms`.
+pub(crate) fn parse_duration(text: &str) -> Result<Duration, String> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .ok_or_else(|| format!("missing unit in {:?}", text))?;
+    let (number, unit) = text.split_at(split);
+    let nanos_per_unit = UNITS
+        .iter()
+        .find(|(name, _)| *name == unit)
+        .map(|(_, nanos)| *nanos)
+        .ok_or_else(|| format!("unknown unit in {:?}", text))?;
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    let whole: u64 = whole
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", text))?;
+    let too_large = || format!("duration {:?} is too large", text);
+    // Parse the fraction digit by digit, so durations like 0.1s are exact
+    let mut nanos = whole.checked_mul(nanos_per_unit).ok_or_else(too_large)?;
+    let mut scale = nanos_per_unit;
+    for digit in fraction.chars() {
+        let digit = digit
+            .to_digit(10)
+            .ok_or_else(|| format!("invalid duration {:?}", text))?;
+        scale /= 10;
+        nanos = nanos.checked_add(digit as u64 * scale).ok_or_else(too_large)?;
+    }
+    Ok(Duration::from_nanos(nanos))
+}
+
+/// Formats a duration in the largest unit that represents it exactly.
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let nanos = duration.as_nanos() as u64;
+    let (unit, nanos_per_unit) = UNITS
+        .iter()
+        .find(|(_, per_unit)| nanos.is_multiple_of(*per_unit) && nanos >= *per_unit)
+        .copied()
+        .unwrap_or(("s", 1_000_000_000));
+    format!("{}{}", nanos / nanos_per_unit, unit)
+}
+
+/// A real time clock that never goes backwards: wall clock time when it was created, advanced by
+/// a monotonic [Instant].
+pub(crate) struct MonotonicClock {
+    start: SimTime,
+    instant: Instant,
+}
+
+impl MonotonicClock {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: SystemTime::now().into(),
+            instant: Instant::now(),
+        }
+    }
+
+    pub(crate) fn now(&self) -> SimTime {
+        self.start + self.instant.elapsed()
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::message_bus::{DropReason, Envelope, Message, PublishHook, SerializedMessage, SimTime};
+
+/// Something that happened during a [crate::message_bus::MessageBus] or
+/// [crate::message_bus::Simulator] run, as seen by a [Recorder].
+pub enum TraceEvent {
+    /// Every running subscriber was ticked.
+    Tick { at: SimTime },
+    /// A subscriber's timer fired, `at` is the time it was set for.
+    Timer {
+        destination: String,
+        token: u64,
+        at: SimTime,
+    },
+    /// An envelope was published. Envelopes published from outside (with an empty `source`)
+    /// carry a copy of their message if it implements [Message::try_clone], so they can be
+    /// replayed; envelopes emitted by subscribers are emitted again when replaying.
+    ///
+    /// `payload` holds the message contents for trace files, if the message implements
+    /// [Message::to_serialized]. `message_type` and `description` come from
+    /// [Message::type_name] and [Message::describe].
+    Publish {
+        id: u64,
+        source: String,
+        destination: String,
+        priority: usize,
+        delay: std::time::Duration,
+        at: SimTime,
+        message_type: String,
+        description: Option<String>,
+        payload: Option<SerializedMessage>,
+        message: Option<Box<dyn Message>>,
+    },
+    /// An envelope was handed to its destination.
+    Deliver {
+        id: u64,
+        destination: String,
+        at: SimTime,
+    },
+    /// An envelope will never be delivered.
+    Drop {
+        id: u64,
+        destination: String,
+        reason: DropReason,
+        at: SimTime,
+    },
+    /// A subscriber crashed.
+    Crash { destination: String, at: SimTime },
+    /// A crashed subscriber restarted.
+    Restart { destination: String, at: SimTime },
+}
+
+impl TraceEvent {
+    /// The time the event happened at.
+    pub fn at(&self) -> SimTime {
+        match self {
+            TraceEvent::Tick { at }
+            | TraceEvent::Timer { at, .. }
+            | TraceEvent::Publish { at, .. }
+            | TraceEvent::Deliver { at, .. }
+            | TraceEvent::Drop { at, .. }
+            | TraceEvent::Crash { at, .. }
+            | TraceEvent::Restart { at, .. } => *at,
+        }
+    }
+}
+
+impl std::fmt::Debug for TraceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceEvent::Tick { at } => f.debug_struct("Tick").field("at", at).finish(),
+            TraceEvent::Timer { destination, token, at } => f
+                .debug_struct("Timer")
+                .field("destination", destination)
+                .field("token", token)
+                .field("at", at)
+                .finish(),
+            TraceEvent::Publish {
+                id,
+                source,
+                destination,
+                priority,
+                delay,
+                at,
+                message_type,
+                description,
+                payload,
+                message,
+            } => f
+                .debug_struct("Publish")
+                .field("id", id)
+                .field("source", source)
+                .field("destination", destination)
+                .field("priority", priority)
+                .field("delay", delay)
+                .field("at", at)
+                .field("message_type", message_type)
+                .field("description", description)
+                .field("payload", payload)
+                .field("message", &message.is_some())
+                .finish(),
+            TraceEvent::Deliver { id, destination, at } => f
+                .debug_struct("Deliver")
+                .field("id", id)
+                .field("destination", destination)
+                .field("at", at)
+                .finish(),
+            TraceEvent::Drop {
+                id,
+                destination,
+                reason,
+                at,
+            } => f
+                .debug_struct("Drop")
+                .field("id", id)
+                .field("destination", destination)
+                .field("reason", reason)
+                .field("at", at)
+                .finish(),
+            TraceEvent::Crash { destination, at } => f
+                .debug_struct("Crash")
+                .field("destination", destination)
+                .field("at", at)
+                .finish(),
+            TraceEvent::Restart { destination, at } => f
+                .debug_struct("Restart")
+                .field("destination", destination)
+                .field("at", at)
+                .finish(),
+        }
+    }
+}
+
+/// The events of a run, in the order they happened.
+///
+/// Save it to a file with [Trace::write_to], and load it back with [Trace::read_from].
+#[derive(Debug, Default)]
+pub struct Trace {
+    pub events: Vec<TraceEvent>,
+}
+
+/// A [PublishHook] that records every tick, timer, publish, delivery, drop, crash and restart
+/// into a [Trace].
+///
+/// Clones share the same trace, so keep a clone around to read the trace after handing the
+/// recorder to a [crate::message_bus::MessageBus] or [crate::message_bus::Simulator]. Replay it
+/// with [crate::message_bus::Simulator::replay].
+#[derive(Clone, Default)]
+pub struct Recorder {
+    events: Arc<Mutex<VecDeque<TraceEvent>>>,
+    /// Maximum number of events kept, oldest first out
+    limit: Option<usize>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A recorder that only keeps the last `limit` events, to look at what led up to a failure.
+    pub fn last(limit: usize) -> Self {
+        Self {
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(limit))),
+            limit: Some(limit),
+        }
+    }
+
+    /// Takes everything recorded so far, leaving the recorder empty.
+    pub fn take(&self) -> Trace {
+        let events = std::mem::take(&mut *self.events.lock().unwrap());
+        Trace {
+            events: events.into(),
+        }
+    }
+
+    fn record(&self, event: TraceEvent) {
+        let mut events = self.events.lock().unwrap();
+        events.push_back(event);
+        if self.limit.is_some_and(|limit| events.len() > limit) {
+            events.pop_front();
+        }
+    }
+}
+
+impl PublishHook for Recorder {
+    fn on_publish(&self, envelope: &Envelope, at: SimTime) {
+        let message = if envelope.source.is_empty() {
+            envelope.message.try_clone()
+        } else {
+            None
+        };
+        self.record(TraceEvent::Publish {
+            id: envelope.id,
+            source: envelope.source.clone(),
+            destination: envelope.destination.clone(),
+            priority: envelope.priority,
+            delay: envelope.delay,
+            at,
+            message_type: envelope.message.type_name().to_string(),
+            description: envelope.message.describe(),
+            payload: envelope.message.to_serialized(),
+            message,
+        });
+    }
+
+    fn on_drop(&self, envelope: &Envelope, reason: DropReason, at: SimTime) {
+        self.record(TraceEvent::Drop {
+            id: envelope.id,
+            destination: envelope.destination.clone(),
+            reason,
+            at,
+        });
+    }
+
+    fn on_tick(&self, at: SimTime) {
+        self.record(TraceEvent::Tick { at });
+    }
+
+    fn on_timer(&self, destination: &str, token: u64, at: SimTime) {
+        self.record(TraceEvent::Timer {
+            destination: destination.to_string(),
+            token,
+            at,
+        });
+    }
+
+    fn on_deliver(&self, envelope: &Envelope, at: SimTime) {
+        self.record(TraceEvent::Deliver {
+            id: envelope.id,
+            destination: envelope.destination.clone(),
+            at,
+        });
+    }
+
+    fn on_crash(&self, destination: &str, at: SimTime) {
+        self.record(TraceEvent::Crash {
+            destination: destination.to_string(),
+            at,
+        });
+    }
+
+    fn on_restart(&self, destination: &str, at: SimTime) {
+        self.record(TraceEvent::Restart {
+            destination: destination.to_string(),
+            at,
+        });
+    }
+}
@@ -0,0 +1,728 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::message_bus::{DropReason, Message, SimTime, Trace, TraceEvent};
+
+/// The version of the trace file formats written by [Trace::write_to]. Reading a trace written
+/// with a different version fails.
+pub const TRACE_FORMAT_VERSION: u32 = 1;
+
+/// First bytes of a binary trace file, followed by the version as a little endian `u32`
+const BINARY_MAGIC: &[u8; 8] = b"DSIMTRC\0";
+
+/// The on-disk encoding of a [Trace].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One JSON object per line, starting with a `{"dsim_trace":<version>}` header line. Easy to
+    /// read, grep, and process with other tools.
+    JsonLines,
+    /// A compact binary encoding, starting with a magic number and the version.
+    Binary,
+}
+
+/// The contents of a message in a trace file, see [Message::to_serialized].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerializedMessage {
+    /// Identifies the message type, to find the decoder in [MessageCodecs] when reading.
+    pub kind: String,
+    /// The encoded message, in any text format the message type likes.
+    pub data: String,
+}
+
+impl SerializedMessage {
+    pub fn new(kind: impl Into<String>, data: impl Into<String>) -> Self {
+        Self {
+            kind: kind.into(),
+            data: data.into(),
+        }
+    }
+
+    /// Serializes a message as JSON, for use in [Message::to_serialized].
+    #[cfg(feature = "serde")]
+    pub fn json<T: serde::Serialize>(kind: impl Into<String>, message: &T) -> Option<Self> {
+        let data = serde_json::to_string(message).ok()?;
+        Some(Self::new(kind, data))
+    }
+}
+
+type Decoder = Box<dyn Fn(&str) -> Option<Box<dyn Message>> + Send + Sync>;
+
+/// Decodes the [SerializedMessage]s of a trace file back into messages, by their kind.
+///
+/// Only envelopes published from outside need their message back to be replayed, see
+/// [crate::message_bus::Simulator::start_replay].
+#[derive(Default)]
+pub struct MessageCodecs {
+    decoders: HashMap<String, Decoder>,
+}
+
+impl MessageCodecs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes messages of `kind` with `decode`.
+    pub fn with_decoder(
+        mut self,
+        kind: impl Into<String>,
+        decode: impl Fn(&str) -> Option<Box<dyn Message>> + Send + Sync + 'static,
+    ) -> Self {
+        self.decoders.insert(kind.into(), Box::new(decode));
+        self
+    }
+
+    /// Decodes messages of `kind` from the JSON written by [SerializedMessage::json].
+    #[cfg(feature = "serde")]
+    pub fn with_json<T: Message + serde::de::DeserializeOwned>(self, kind: impl Into<String>) -> Self {
+        self.with_decoder(kind, |data| {
+            let message: T = serde_json::from_str(data).ok()?;
+            Some(Box::new(message) as Box<dyn Message>)
+        })
+    }
+
+    fn decode(&self, message: &SerializedMessage) -> Option<Box<dyn Message>> {
+        (self.decoders.get(&message.kind)?)(&message.data)
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason.into())
+}
+
+fn drop_reason_name(reason: DropReason) -> &'static str {
+    match reason {
+        DropReason::Fault => "fault",
+        DropReason::Partitioned => "partitioned",
+        DropReason::Crashed => "crashed",
+        DropReason::Unsubscribed => "unsubscribed",
+        DropReason::UnknownDestination => "unknown_destination",
+    }
+}
+
+fn drop_reason_from_name(name: &str) -> std::io::Result<DropReason> {
+    match name {
+        "fault" => Ok(DropReason::Fault),
+        "partitioned" => Ok(DropReason::Partitioned),
+        "crashed" => Ok(DropReason::Crashed),
+        "unsubscribed" => Ok(DropReason::Unsubscribed),
+        "unknown_destination" => Ok(DropReason::UnknownDestination),
+        _ => Err(invalid(format!("unknown drop reason {name:?}"))),
+    }
+}
+
+impl Trace {
+    /// Writes the trace in the given format. Message contents are written for messages that
+    /// implement [Message::to_serialized].
+    pub fn write_to(&self, writer: impl Write, format: TraceFormat) -> std::io::Result<()> {
+        let mut writer = std::io::BufWriter::new(writer);
+        match format {
+            TraceFormat::JsonLines => {
+                writeln!(writer, "{{\"dsim_trace\":{TRACE_FORMAT_VERSION}}}")?;
+                for event in self.events.iter() {
+                    writeln!(writer, "{}", json::encode(event))?;
+                }
+            }
+            TraceFormat::Binary => {
+                writer.write_all(BINARY_MAGIC)?;
+                writer.write_all(&TRACE_FORMAT_VERSION.to_le_bytes())?;
+                for event in self.events.iter() {
+                    binary::encode(&mut writer, event)?;
+                }
+            }
+        }
+        writer.flush()
+    }
+
+    /// Reads a trace written by [Trace::write_to] in either format, detecting which one.
+    ///
+    /// Messages of envelopes published from outside are decoded with `codecs`, so that the trace
+    /// can be replayed.
+    pub fn read_from(reader: impl Read, codecs: &MessageCodecs) -> std::io::Result<Trace> {
+        let mut reader = BufReader::new(reader);
+        // Read the whole prefix, a single read may return fewer bytes (e.g. from a pipe)
+        let mut prefix = vec![];
+        (&mut reader)
+            .take(BINARY_MAGIC.len() as u64)
+            .read_to_end(&mut prefix)?;
+        let mut events = if prefix == BINARY_MAGIC {
+            binary::decode(&mut reader)?
+        } else {
+            json::decode(&mut prefix.as_slice().chain(reader))?
+        };
+        for event in events.iter_mut() {
+            if let TraceEvent::Publish {
+                source,
+                payload: Some(payload),
+                message,
+                ..
+            } = event
+                && source.is_empty()
+            {
+                *message = codecs.decode(payload);
+            }
+        }
+        Ok(Trace { events })
+    }
+}
+
+mod json {
+    use super::*;
+
+    fn string(out: &mut String, value: &str) {
+        out.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+
+    /// Builds a flat JSON object
+    struct Object(String);
+
+    impl Object {
+        fn new(event: &str) -> Self {
+            let mut object = Object(String::from("{"));
+            object.str("event", event);
+            object
+        }
+
+        fn key(&mut self, key: &str) {
+            if self.0.len() > 1 {
+                self.0.push(',');
+            }
+            string(&mut self.0, key);
+            self.0.push(':');
+        }
+
+        fn str(&mut self, key: &str, value: &str) {
+            self.key(key);
+            string(&mut self.0, value);
+        }
+
+        fn num(&mut self, key: &str, value: u64) {
+            self.key(key);
+            self.0.push_str(&value.to_string());
+        }
+
+        fn finish(mut self) -> String {
+            self.0.push('}');
+            self.0
+        }
+    }
+
+    pub(super) fn encode(event: &TraceEvent) -> String {
+        match event {
+            TraceEvent::Tick { at } => {
+                let mut object = Object::new("tick");
+                object.num("at", at.as_nanos());
+                object.finish()
+            }
+            TraceEvent::Timer { destination, token, at } => {
+                let mut object = Object::new("timer");
+                object.str("destination", destination);
+                object.num("token", *token);
+                object.num("at", at.as_nanos());
+                object.finish()
+            }
+            TraceEvent::Publish {
+                id,
+                source,
+                destination,
+                priority,
+                delay,
+                at,
+                message_type,
+                description,
+                payload,
+                ..
+            } => {
+                let mut object = Object::new("publish");
+                object.num("id", *id);
+                object.str("source", source);
+                object.str("destination", destination);
+                object.num("priority", *priority as u64);
+                object.num("delay", delay.as_nanos() as u64);
+                object.num("at", at.as_nanos());
+                object.str("type", message_type);
+                if let Some(description) = description {
+                    object.str("description", description);
+                }
+                if let Some(payload) = payload {
+                    object.str("kind", &payload.kind);
+                    object.str("data", &payload.data);
+                }
+                object.finish()
+            }
+            TraceEvent::Deliver { id, destination, at } => {
+                let mut object = Object::new("deliver");
+                object.num("id", *id);
+                object.str("destination", destination);
+                object.num("at", at.as_nanos());
+                object.finish()
+            }
+            TraceEvent::Drop {
+                id,
+                destination,
+                reason,
+                at,
+            } => {
+                let mut object = Object::new("drop");
+                object.num("id", *id);
+                object.str("destination", destination);
+                object.str("reason", drop_reason_name(*reason));
+                object.num("at", at.as_nanos());
+                object.finish()
+            }
+            TraceEvent::Crash { destination, at } => {
+                let mut object = Object::new("crash");
+                object.str("destination", destination);
+                object.num("at", at.as_nanos());
+                object.finish()
+            }
+            TraceEvent::Restart { destination, at } => {
+                let mut object = Object::new("restart");
+                object.str("destination", destination);
+                object.num("at", at.as_nanos());
+                object.finish()
+            }
+        }
+    }
+
+    enum Value {
+        Str(String),
+        Num(u64),
+    }
+
+    /// A flat JSON object with string and unsigned integer values, as written by [encode]
+    struct Fields(HashMap<String, Value>);
+
+    impl Fields {
+        fn str(&mut self, key: &str) -> std::io::Result<String> {
+            match self.0.remove(key) {
+                Some(Value::Str(value)) => Ok(value),
+                _ => Err(invalid(format!("missing string field {key:?}"))),
+            }
+        }
+
+        fn num(&mut self, key: &str) -> std::io::Result<u64> {
+            match self.0.remove(key) {
+                Some(Value::Num(value)) => Ok(value),
+                _ => Err(invalid(format!("missing number field {key:?}"))),
+            }
+        }
+
+        fn at(&mut self) -> std::io::Result<SimTime> {
+            Ok(SimTime::from_nanos(self.num("at")?))
+        }
+    }
+
+    struct Parser<'a> {
+        chars: std::iter::Peekable<std::str::Chars<'a>>,
+    }
+
+    impl Parser<'_> {
+        fn skip_whitespace(&mut self) {
+            while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+                self.chars.next();
+            }
+        }
+
+        fn expect(&mut self, expected: char) -> std::io::Result<()> {
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(c) if c == expected => Ok(()),
+                c => Err(invalid(format!("expected {expected:?}, found {c:?}"))),
+            }
+        }
+
+        fn string(&mut self) -> std::io::Result<String> {
+            self.expect('"')?;
+            let mut out = String::new();
+            loop {
+                match self.chars.next() {
+                    Some('"') => return Ok(out),
+                    Some('\\') => match self.chars.next() {
+                        Some(c @ ('"' | '\\' | '/')) => out.push(c),
+                        Some('b') => out.push('\u{8}'),
+                        Some('f') => out.push('\u{c}'),
+                        Some('n') => out.push('\n'),
+                        Some('r') => out.push('\r'),
+                        Some('t') => out.push('\t'),
+                        Some('u') => out.push(self.unicode_escape()?),
+                        Some(c) => return Err(invalid(format!("invalid escape \\{c}"))),
+                        None => break,
+                    },
+                    Some(c) => out.push(c),
+                    None => break,
+                }
+            }
+            Err(invalid("unterminated string"))
+        }
+
+        /// The four hex digits after `\u`.
+        fn hex4(&mut self) -> std::io::Result<u32> {
+            let hex: String = self.chars.by_ref().take(4).collect();
+            if hex.len() != 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid(format!("invalid escape \\u{hex}")));
+            }
+            Ok(u32::from_str_radix(&hex, 16).unwrap())
+        }
+
+        /// A `\u` escape, combining a UTF-16 surrogate pair into a single character.
+        fn unicode_escape(&mut self) -> std::io::Result<char> {
+            let high = self.hex4()?;
+            let code = match high {
+                0xD800..=0xDBFF => {
+                    if self.chars.next() != Some('\\') || self.chars.next() != Some('u') {
+                        return Err(invalid(format!("unpaired surrogate \\u{high:04x}")));
+                    }
+                    let low = self.hex4()?;
+                    if !(0xDC00..=0xDFFF).contains(&low) {
+                        return Err(invalid(format!("unpaired surrogate \\u{high:04x}")));
+                    }
+                    0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                }
+                code => code,
+            };
+            char::from_u32(code).ok_or_else(|| invalid(format!("invalid escape \\u{code:04x}")))
+        }
+
+        fn value(&mut self) -> std::io::Result<Value> {
+            self.skip_whitespace();
+            if self.chars.peek() == Some(&'"') {
+                return Ok(Value::Str(self.string()?));
+            }
+            let mut digits = String::new();
+            while let Some(c) = self.chars.peek().filter(|c| c.is_ascii_digit()) {
+                digits.push(*c);
+                self.chars.next();
+            }
+            digits
+                .parse()
+                .map(Value::Num)
+                .map_err(|_| invalid("expected a string or an unsigned integer"))
+        }
+
+        fn object(&mut self) -> std::io::Result<Fields> {
+            let mut fields = HashMap::new();
+            self.expect('{')?;
+            self.skip_whitespace();
+            if self.chars.peek() == Some(&'}') {
+                self.chars.next();
+                return Ok(Fields(fields));
+            }
+            loop {
+                let key = self.string()?;
+                self.expect(':')?;
+                fields.insert(key, self.value()?);
+                self.skip_whitespace();
+                match self.chars.next() {
+                    Some(',') => continue,
+                    Some('}') => return Ok(Fields(fields)),
+                    c => return Err(invalid(format!("expected ',' or '}}', found {c:?}"))),
+                }
+            }
+        }
+    }
+
+    fn parse(line: &str) -> std::io::Result<Fields> {
+        Parser {
+            chars: line.chars().peekable(),
+        }
+        .object()
+    }
+
+    fn event(mut fields: Fields) -> std::io::Result<TraceEvent> {
+        let event = fields.str("event")?;
+        Ok(match event.as_str() {
+            "tick" => TraceEvent::Tick { at: fields.at()? },
+            "timer" => TraceEvent::Timer {
+                destination: fields.str("destination")?,
+                token: fields.num("token")?,
+                at: fields.at()?,
+            },
+            "publish" => {
+                let payload = match (fields.str("kind"), fields.str("data")) {
+                    (Ok(kind), Ok(data)) => Some(SerializedMessage { kind, data }),
+                    _ => None,
+                };
+                TraceEvent::Publish {
+                    id: fields.num("id")?,
+                    source: fields.str("source")?,
+                    destination: fields.str("destination")?,
+                    priority: fields.num("priority")? as usize,
+                    delay: std::time::Duration::from_nanos(fields.num("delay")?),
+                    at: fields.at()?,
+                    message_type: fields.str("type")?,
+                    description: fields.str("description").ok(),
+                    payload,
+                    message: None,
+                }
+            }
+            "deliver" => TraceEvent::Deliver {
+                id: fields.num("id")?,
+                destination: fields.str("destination")?,
+                at: fields.at()?,
+            },
+            "drop" => TraceEvent::Drop {
+                id: fields.num("id")?,
+                destination: fields.str("destination")?,
+                reason: drop_reason_from_name(&fields.str("reason")?)?,
+                at: fields.at()?,
+            },
+            "crash" => TraceEvent::Crash {
+                destination: fields.str("destination")?,
+                at: fields.at()?,
+            },
+            "restart" => TraceEvent::Restart {
+                destination: fields.str("destination")?,
+                at: fields.at()?,
+            },
+            _ => return Err(invalid(format!("unknown event {event:?}"))),
+        })
+    }
+
+    pub(super) fn decode(reader: &mut impl BufRead) -> std::io::Result<Vec<TraceEvent>> {
+        let mut lines = reader.lines();
+        let header = lines.next().ok_or_else(|| invalid("empty trace"))??;
+        let version = parse(&header)?.num("dsim_trace")?;
+        if version != TRACE_FORMAT_VERSION as u64 {
+            return Err(invalid(format!(
+                "unsupported trace version {version}, expected {TRACE_FORMAT_VERSION}"
+            )));
+        }
+        let mut events = vec![];
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            events.push(event(parse(&line)?)?);
+        }
+        Ok(events)
+    }
+}
+
+mod binary {
+    use super::*;
+
+    const TICK: u8 = 0;
+    const TIMER: u8 = 1;
+    const PUBLISH: u8 = 2;
+    const DELIVER: u8 = 3;
+    const DROP: u8 = 4;
+    const CRASH: u8 = 5;
+    const RESTART: u8 = 6;
+
+    fn u64(writer: &mut impl Write, value: u64) -> std::io::Result<()> {
+        writer.write_all(&value.to_le_bytes())
+    }
+
+    fn string(writer: &mut impl Write, value: &str) -> std::io::Result<()> {
+        let len = u32::try_from(value.len()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "string longer than 4 GiB")
+        })?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(value.as_bytes())
+    }
+
+    pub(super) fn encode(writer: &mut impl Write, event: &TraceEvent) -> std::io::Result<()> {
+        match event {
+            TraceEvent::Tick { at } => {
+                writer.write_all(&[TICK])?;
+                u64(writer, at.as_nanos())
+            }
+            TraceEvent::Timer { destination, token, at } => {
+                writer.write_all(&[TIMER])?;
+                string(writer, destination)?;
+                u64(writer, *token)?;
+                u64(writer, at.as_nanos())
+            }
+            TraceEvent::Publish {
+                id,
+                source,
+                destination,
+                priority,
+                delay,
+                at,
+                message_type,
+                description,
+                payload,
+                ..
+            } => {
+                writer.write_all(&[PUBLISH])?;
+                u64(writer, *id)?;
+                string(writer, source)?;
+                string(writer, destination)?;
+                u64(writer, *priority as u64)?;
+                u64(writer, delay.as_nanos() as u64)?;
+                u64(writer, at.as_nanos())?;
+                string(writer, message_type)?;
+                match description {
+                    Some(description) => {
+                        writer.write_all(&[1])?;
+                        string(writer, description)?;
+                    }
+                    None => writer.write_all(&[0])?,
+                }
+                match payload {
+                    Some(payload) => {
+                        writer.write_all(&[1])?;
+                        string(writer, &payload.kind)?;
+                        string(writer, &payload.data)
+                    }
+                    None => writer.write_all(&[0]),
+                }
+            }
+            TraceEvent::Deliver { id, destination, at } => {
+                writer.write_all(&[DELIVER])?;
+                u64(writer, *id)?;
+                string(writer, destination)?;
+                u64(writer, at.as_nanos())
+            }
+            TraceEvent::Drop {
+                id,
+                destination,
+                reason,
+                at,
+            } => {
+                writer.write_all(&[DROP])?;
+                u64(writer, *id)?;
+                string(writer, destination)?;
+                string(writer, drop_reason_name(*reason))?;
+                u64(writer, at.as_nanos())
+            }
+            TraceEvent::Crash { destination, at } => {
+                writer.write_all(&[CRASH])?;
+                string(writer, destination)?;
+                u64(writer, at.as_nanos())
+            }
+            TraceEvent::Restart { destination, at } => {
+                writer.write_all(&[RESTART])?;
+                string(writer, destination)?;
+                u64(writer, at.as_nanos())
+            }
+        }
+    }
+
+    struct Reader<'a, R> {
+        reader: &'a mut R,
+    }
+
+    impl<R: Read> Reader<'_, R> {
+        fn u8(&mut self) -> std::io::Result<u8> {
+            let mut buf = [0; 1];
+            self.reader.read_exact(&mut buf)?;
+            Ok(buf[0])
+        }
+
+        fn u32(&mut self) -> std::io::Result<u32> {
+            let mut buf = [0; 4];
+            self.reader.read_exact(&mut buf)?;
+            Ok(u32::from_le_bytes(buf))
+        }
+
+        fn u64(&mut self) -> std::io::Result<u64> {
+            let mut buf = [0; 8];
+            self.reader.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+
+        fn at(&mut self) -> std::io::Result<SimTime> {
+            Ok(SimTime::from_nanos(self.u64()?))
+        }
+
+        fn string(&mut self) -> std::io::Result<String> {
+            let len = self.u32()? as u64;
+            // Read through `take` rather than allocating `len` bytes up front, a corrupt length
+            // would otherwise allocate up to 4 GiB
+            let mut buf = vec![];
+            (&mut *self.reader).take(len).read_to_end(&mut buf)?;
+            if (buf.len() as u64) < len {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            String::from_utf8(buf).map_err(|_| invalid("string is not valid UTF-8"))
+        }
+
+        fn event(&mut self, tag: u8) -> std::io::Result<TraceEvent> {
+            Ok(match tag {
+                TICK => TraceEvent::Tick { at: self.at()? },
+                TIMER => TraceEvent::Timer {
+                    destination: self.string()?,
+                    token: self.u64()?,
+                    at: self.at()?,
+                },
+                PUBLISH => TraceEvent::Publish {
+                    id: self.u64()?,
+                    source: self.string()?,
+                    destination: self.string()?,
+                    priority: self.u64()? as usize,
+                    delay: std::time::Duration::from_nanos(self.u64()?),
+                    at: self.at()?,
+                    message_type: self.string()?,
+                    description: match self.u8()? {
+                        0 => None,
+                        _ => Some(self.string()?),
+                    },
+                    payload: match self.u8()? {
+                        0 => None,
+                        _ => Some(SerializedMessage {
+                            kind: self.string()?,
+                            data: self.string()?,
+                        }),
+                    },
+                    message: None,
+                },
+                DELIVER => TraceEvent::Deliver {
+                    id: self.u64()?,
+                    destination: self.string()?,
+                    at: self.at()?,
+                },
+                DROP => TraceEvent::Drop {
+                    id: self.u64()?,
+                    destination: self.string()?,
+                    reason: drop_reason_from_name(&self.string()?)?,
+                    at: self.at()?,
+                },
+                CRASH => TraceEvent::Crash {
+                    destination: self.string()?,
+                    at: self.at()?,
+                },
+                RESTART => TraceEvent::Restart {
+                    destination: self.string()?,
+                    at: self.at()?,
+                },
+                tag => return Err(invalid(format!("unknown event tag {tag}"))),
+            })
+        }
+    }
+
+    /// Decodes the events following the magic number.
+    pub(super) fn decode(reader: &mut impl BufRead) -> std::io::Result<Vec<TraceEvent>> {
+        let mut reader = Reader { reader };
+        let version = reader.u32()?;
+        if version != TRACE_FORMAT_VERSION {
+            return Err(invalid(format!(
+                "unsupported trace version {version}, expected {TRACE_FORMAT_VERSION}"
+            )));
+        }
+        let mut events = vec![];
+        loop {
+            let tag = match reader.u8() {
+                Ok(tag) => tag,
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            };
+            events.push(reader.event(tag)?);
+        }
+        Ok(events)
+    }
+}